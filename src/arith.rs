@@ -0,0 +1,367 @@
+// Arithmetic expansion, `$((...))`:
+// https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_04
+// By the time we get here, parameters and such have already been expanded.
+// Everything is a signed 64 bit integer, and overflow wraps around like it does in C.
+use crate::helpers::Shell;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Num(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+// Longest first, so `<<=` doesn't get mistaken for `<`
+const OPS: [&str; 38] = [
+    "<<=", ">>=", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "++", "--", "+=", "-=", "*=", "/=", "%=",
+    "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~", "?", ":", "=",
+    "(", ")", ",",
+];
+
+// Binary operators, from loosest to tightest
+const LEVELS: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", ">", "<=", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+#[derive(Debug)]
+enum Node {
+    Num(i64),
+    Var(String),
+    Unary(&'static str, Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+    // The operator that goes along with the `=`, if any, like `+` for `+=`
+    Assign(String, Option<&'static str>, Box<Node>),
+    Cond(Box<Node>, Box<Node>, Box<Node>),
+    // `++` and `--`: by how much, and whether it gives back the new value
+    Incr(String, i64, bool),
+}
+
+pub fn evaluate(expr: &str, shell: &Rc<RefCell<Shell>>) -> Result<i64, String> {
+    let tokens = tokenize(expr)?;
+    // Nothing at all is as good as zero
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+    let mut parser = Parser { tokens, pos: 0 };
+    let node = parser.expression()?;
+    match parser.tokens.get(parser.pos) {
+        None => eval(&node, shell),
+        Some(token) => Err(format!("syntax error near `{}`", show(token))),
+    }
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if c.is_ascii_digit() {
+                tokens.push(Token::Num(number(&word)?));
+            } else {
+                tokens.push(Token::Name(word));
+            }
+        } else {
+            let rest: String = chars[i..].iter().take(3).collect();
+            let op = OPS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| format!("syntax error near `{}`", c))?;
+            i += op.len();
+            // `++` and `--` only mean anything next to a variable, otherwise
+            // they're just two signs, like in `1--1`
+            let next = chars[i..].iter().find(|c| !c.is_whitespace());
+            let by_name = matches!(tokens.last(), Some(Token::Name(_)))
+                || next.is_some_and(|c| c.is_ascii_alphabetic() || *c == '_');
+            if matches!(*op, "++" | "--") && !by_name {
+                let sign = &op[..1];
+                tokens.push(Token::Op(sign));
+                tokens.push(Token::Op(sign));
+            } else {
+                tokens.push(Token::Op(op));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// Decimal, octal with a leading 0, or hex with a leading 0x
+fn number(s: &str) -> Result<i64, String> {
+    let result = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16)
+    } else if s.len() > 1 && s.starts_with('0') {
+        u64::from_str_radix(&s[1..], 8)
+    } else {
+        s.parse()
+    };
+    result.map(|n| n as i64).map_err(|_| format!("{}: bad number", s))
+}
+
+fn show(token: &Token) -> String {
+    match token {
+        Token::Num(n) => n.to_string(),
+        Token::Name(name) => name.clone(),
+        Token::Op(op) => op.to_string(),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected `{}`", op))
+        }
+    }
+
+    // The comma is the loosest of them all
+    fn expression(&mut self) -> Result<Node, String> {
+        let mut node = self.assignment()?;
+        while self.peek_op() == Some(",") {
+            self.pos += 1;
+            node = Node::Binary(",", Box::new(node), Box::new(self.assignment()?));
+        }
+        Ok(node)
+    }
+
+    fn assignment(&mut self) -> Result<Node, String> {
+        if let (Some(Token::Name(name)), Some(Token::Op(op))) =
+            (self.tokens.get(self.pos), self.tokens.get(self.pos + 1))
+        {
+            if op.ends_with('=') && !matches!(*op, "==" | "!=" | "<=" | ">=") {
+                let name = name.clone();
+                let op = if *op == "=" { None } else { Some(&op[..op.len() - 1]) };
+                self.pos += 2;
+                return Ok(Node::Assign(name, op, Box::new(self.assignment()?)));
+            }
+        }
+        self.conditional()
+    }
+
+    fn conditional(&mut self) -> Result<Node, String> {
+        let cond = self.binary(0)?;
+        if self.peek_op() == Some("?") {
+            self.pos += 1;
+            let then = self.expression()?;
+            self.expect(":")?;
+            let otherwise = self.conditional()?;
+            Ok(Node::Cond(Box::new(cond), Box::new(then), Box::new(otherwise)))
+        } else {
+            Ok(cond)
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut node = self.binary(level + 1)?;
+        while let Some(op) = self.peek_op().filter(|op| LEVELS[level].contains(op)) {
+            self.pos += 1;
+            node = Node::Binary(op, Box::new(node), Box::new(self.binary(level + 1)?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Op(op)) if matches!(op, "+" | "-" | "!" | "~") => {
+                Ok(Node::Unary(op, Box::new(self.unary()?)))
+            }
+            Some(Token::Op(op)) if matches!(op, "++" | "--") => match self.tokens.get(self.pos).cloned() {
+                Some(Token::Name(name)) => {
+                    self.pos += 1;
+                    Ok(Node::Incr(name, if op == "++" { 1 } else { -1 }, true))
+                }
+                _ => Err(format!("syntax error near `{}`", op)),
+            },
+            Some(Token::Op("(")) => {
+                let node = self.expression()?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Some(Token::Num(n)) => Ok(Node::Num(n)),
+            Some(Token::Name(name)) => match self.peek_op() {
+                Some(op) if matches!(op, "++" | "--") => {
+                    self.pos += 1;
+                    Ok(Node::Incr(name, if op == "++" { 1 } else { -1 }, false))
+                }
+                _ => Ok(Node::Var(name)),
+            },
+            Some(token) => Err(format!("syntax error near `{}`", show(&token))),
+            None => Err(String::from("expected an expression")),
+        }
+    }
+}
+
+// `&&`, `||` and `?:` only look at the side they need, so assignments on the
+// other side never happen
+fn eval(node: &Node, shell: &Rc<RefCell<Shell>>) -> Result<i64, String> {
+    Ok(match node {
+        Node::Num(n) => *n,
+        Node::Var(name) => variable(name, shell)?,
+        Node::Unary(op, node) => {
+            let n = eval(node, shell)?;
+            match *op {
+                "-" => n.wrapping_neg(),
+                "!" => (n == 0) as i64,
+                "~" => !n,
+                _ => n,
+            }
+        }
+        Node::Binary("&&", left, right) => (eval(left, shell)? != 0 && eval(right, shell)? != 0) as i64,
+        Node::Binary("||", left, right) => (eval(left, shell)? != 0 || eval(right, shell)? != 0) as i64,
+        Node::Binary(",", left, right) => {
+            eval(left, shell)?;
+            eval(right, shell)?
+        }
+        Node::Binary(op, left, right) => apply(op, eval(left, shell)?, eval(right, shell)?)?,
+        Node::Cond(cond, then, otherwise) => {
+            if eval(cond, shell)? != 0 {
+                eval(then, shell)?
+            } else {
+                eval(otherwise, shell)?
+            }
+        }
+        Node::Assign(name, op, value) => {
+            let mut value = eval(value, shell)?;
+            if let Some(op) = op {
+                value = apply(op, variable(name, shell)?, value)?;
+            }
+            shell.borrow_mut().set_var(name.clone(), value.to_string())?;
+            value
+        }
+        Node::Incr(name, by, prefix) => {
+            let old = variable(name, shell)?;
+            let new = old.wrapping_add(*by);
+            shell.borrow_mut().set_var(name.clone(), new.to_string())?;
+            if *prefix { new } else { old }
+        }
+    })
+}
+
+fn apply(op: &str, left: i64, right: i64) -> Result<i64, String> {
+    Ok(match op {
+        "/" | "%" if right == 0 => return Err(String::from("division by zero")),
+        "+" => left.wrapping_add(right),
+        "-" => left.wrapping_sub(right),
+        "*" => left.wrapping_mul(right),
+        "/" => left.wrapping_div(right),
+        "%" => left.wrapping_rem(right),
+        "<<" => left.wrapping_shl(right as u32),
+        ">>" => left.wrapping_shr(right as u32),
+        "<" => (left < right) as i64,
+        ">" => (left > right) as i64,
+        "<=" => (left <= right) as i64,
+        ">=" => (left >= right) as i64,
+        "==" => (left == right) as i64,
+        "!=" => (left != right) as i64,
+        "&" => left & right,
+        "^" => left ^ right,
+        "|" => left | right,
+        _ => unreachable!(),
+    })
+}
+
+// Unset and empty variables count as zero, anything else has to be a number
+fn variable(name: &str, shell: &Rc<RefCell<Shell>>) -> Result<i64, String> {
+    let val = shell.borrow().get_var(name).unwrap_or_default();
+    let val = val.trim();
+    if val.is_empty() {
+        Ok(0)
+    } else if let Some(val) = val.strip_prefix('-') {
+        number(val).map(i64::wrapping_neg)
+    } else {
+        number(val.strip_prefix('+').unwrap_or(val))
+    }
+}
+
+#[cfg(test)]
+mod arith_tests {
+    use super::evaluate;
+    use crate::helpers::Shell;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_numbers() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        assert_eq!(Ok(42), evaluate("42", &shell));
+        assert_eq!(Ok(8), evaluate("010", &shell));
+        assert_eq!(Ok(255), evaluate("0xff", &shell));
+        assert_eq!(Ok(0), evaluate("  ", &shell));
+        assert!(evaluate("08", &shell).is_err());
+    }
+
+    #[test]
+    fn test_operators() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        assert_eq!(Ok(7), evaluate("1 + 2 * 3", &shell));
+        assert_eq!(Ok(9), evaluate("(1 + 2) * 3", &shell));
+        assert_eq!(Ok(-1), evaluate("-7 / 4 + 1 % 3 - 1", &shell));
+        assert_eq!(Ok(1), evaluate("3 > 2 && !0", &shell));
+        assert_eq!(Ok(6), evaluate("1 << 2 | 2 ^ 0", &shell));
+        assert_eq!(Ok(-6), evaluate("~5", &shell));
+        assert_eq!(Ok(20), evaluate("0 ? 10 : 1 ? 20 : 30", &shell));
+        assert_eq!(Ok(3), evaluate("1, 2, 3", &shell));
+    }
+
+    #[test]
+    fn test_assignment() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        assert_eq!(Ok(5), evaluate("x = 5", &shell));
+        assert_eq!(Ok(8), evaluate("x += 3", &shell));
+        assert_eq!(Ok(32), evaluate("x <<= 2", &shell));
+        assert_eq!(Some(String::from("32")), shell.borrow().get_var("x"));
+        assert_eq!(Ok(32), evaluate("x--", &shell));
+        assert_eq!(Ok(32), evaluate("++x", &shell));
+        assert_eq!(Ok(2), evaluate("1--1", &shell));
+        // The side that doesn't need to run doesn't
+        assert_eq!(Ok(0), evaluate("0 && (y = 1)", &shell));
+        assert_eq!(None, shell.borrow().get_var("y"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        assert_eq!(Err(String::from("division by zero")), evaluate("1 / 0", &shell));
+        assert!(evaluate("1 +", &shell).is_err());
+        assert!(evaluate("(1", &shell).is_err());
+        assert!(evaluate("1 2", &shell).is_err());
+        assert!(evaluate("1 @ 2", &shell).is_err());
+    }
+}
@@ -1,37 +1,524 @@
 use std::process::exit as exit_program;
 use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor};
 use std::rc::Rc;
 use std::cell::RefCell;
-use crate::helpers::Shell;
+use crate::helpers::{is_name, Shell, Skip, Var};
+use crate::runner::Runner;
+
+// Special builtins can change the shell in ways that outlast them, so assignments in
+// front of them stick around afterwards too. Assignments in front of anything else,
+// regular builtins included, only last as long as the command.
+pub const SPECIAL: &[&str] = &[
+    ".", ":", "break", "continue", "eval", "exec", "exit", "export", "readonly", "return", "set", "shift", "unset",
+];
+pub const REGULAR: &[&str] = &["cd", "getopts"];
 
 // Unless specified otherwise, if provided multiple arguments while only
 // accepting one, these use the first argument. Dash does this as well.  
 
-pub fn exit(args: Vec<String>) -> bool {
-    match args.get(0).map_or(Ok(0), |x| x.parse::<i32>()) {
+// Without a number, goes with whatever the last command did
+pub fn exit(args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> i32 {
+    match args.first().map_or(Ok(shell.borrow().status), |x| x.parse::<i32>()) {
         Ok(n) => {
             exit_program(n);
         },
-        Err(e) => {
-            eprintln!("rush: {}", e);
-            false
+        Err(_) => {
+            eprintln!("rush: exit: {}: numeric argument required", args[0]);
+            2
         },
     }
 }
 
-pub fn cd(args: Vec<String>) -> bool {
-    let new_dir = args.into_iter().next().unwrap_or_else(|| env::var("HOME").unwrap());
+pub fn cd(args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> i32 {
+    let new_dir = match args.into_iter().next().or_else(|| shell.borrow().get_var("HOME")) {
+        Some(dir) => dir,
+        None => {
+            eprintln!("rush: cd: HOME not set");
+            return 1;
+        }
+    };
     if let Err(e) = env::set_current_dir(new_dir) {
         eprintln!("rush: {}", e);
-        false
+        1
+    } else {
+        0
+    }
+}
+
+// Set very versetaile normally, this is just options and positional parameters for now.
+// `-` turns an option on, `+` turns it off, and anything after them (or after `--`)
+// becomes the positional parameters. `-o` and `+o` take an option's name instead,
+// or without one, list them all.
+pub fn set(args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> i32 {
+    let mut args = args.into_iter().peekable();
+    let mut replace = false;
+    while let Some(arg) = args.peek() {
+        if arg == "--" {
+            args.next();
+            replace = true;
+            break;
+        }
+        let on = match arg.chars().next() {
+            Some('-') => true,
+            Some('+') => false,
+            _ => break,
+        };
+        if arg.len() == 1 {
+            break;
+        }
+        let arg = args.next().unwrap();
+        for c in arg.chars().skip(1) {
+            let sign = if on { '-' } else { '+' };
+            if c == 'o' {
+                match args.next() {
+                    Some(name) => {
+                        if !shell.borrow_mut().options.set_named(&name, on) {
+                            eprintln!("rush: set: {}o {}: invalid option name", sign, name);
+                            return 2;
+                        }
+                    }
+                    None => list_options(shell, on),
+                }
+            } else if !shell.borrow_mut().options.set(c, on) {
+                eprintln!("rush: set: {}{}: invalid option", sign, c);
+                return 2;
+            }
+        }
+    }
+    let args: Vec<String> = args.collect();
+    if replace || !args.is_empty() {
+        shell.borrow_mut().set_pos(args);
+    }
+    0
+}
+
+// `set -o` is meant for people to read, `set +o` for the shell to read back in
+fn list_options(shell: &Rc<RefCell<Shell>>, readable: bool) {
+    for (name, on) in shell.borrow().options.named() {
+        if readable {
+            println!("{:<15} {}", name, if on { "on" } else { "off" });
+        } else {
+            println!("set {}o {}", if on { '-' } else { '+' }, name);
+        }
+    }
+}
+
+
+pub fn break_loop(args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> i32 {
+    if let Some(n) = skip_loops("break", args, shell) {
+        if n > 0 {
+            shell.borrow_mut().skip = Some(Skip::Break(n));
+        }
+        0
+    } else {
+        1
+    }
+}
+
+pub fn continue_loop(args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> i32 {
+    if let Some(n) = skip_loops("continue", args, shell) {
+        if n > 0 {
+            shell.borrow_mut().skip = Some(Skip::Continue(n));
+        }
+        0
     } else {
-        true
+        1
+    }
+}
+
+pub fn return_function(args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> i32 {
+    if shell.borrow().calls == 0 && shell.borrow().dots == 0 {
+        eprintln!("rush: return: can only return from a function or sourced script");
+        return 1;
+    }
+    let status = shell.borrow().status;
+    match args.first().map_or(Ok(status), |x| x.parse::<i32>()) {
+        Ok(n) => {
+            shell.borrow_mut().skip = Some(Skip::Return(n));
+            n
+        }
+        Err(_) => {
+            eprintln!("rush: return: {}: numeric argument required", args[0]);
+            2
+        }
+    }
+}
+
+// How many loops `break` and `continue` should get out of. Asking for more loops
+// than we're in means all of them, and outside of a loop it's none.
+fn skip_loops(name: &str, args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> Option<u32> {
+    match args.first().map_or(Ok(1), |x| x.parse::<u32>()) {
+        Ok(0) | Err(_) => {
+            eprintln!("rush: {}: {}: bad number", name, args[0]);
+            None
+        }
+        Ok(n) => Some(n.min(shell.borrow().loops)),
+    }
+}
+
+pub fn export(args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> i32 {
+    declare("export", args, shell, |var| var.exported = true, |var| var.exported)
+}
+
+pub fn readonly(args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> i32 {
+    declare("readonly", args, shell, |var| var.readonly = true, |var| var.readonly)
+}
+
+// `export` and `readonly` both give variables an attribute, setting them on the way if
+// there's a value. With `-p` or nothing at all, they list every variable that has it
+// in a way that can be read back in.
+fn declare(
+    name: &str,
+    args: Vec<String>,
+    shell: &Rc<RefCell<Shell>>,
+    give: fn(&mut Var),
+    has: fn(&Var) -> bool,
+) -> i32 {
+    let mut args = args.into_iter().peekable();
+    match args.peek().map(String::as_str) {
+        Some("-p") | None => {
+            let shell = shell.borrow();
+            let mut vars: Vec<_> = shell.vars.iter().filter(|(_, var)| has(var)).collect();
+            vars.sort_by_key(|(key, _)| key.as_str());
+            for (key, var) in vars {
+                match &var.value {
+                    Some(value) => println!("{} {}={}", name, key, quote(value)),
+                    None => println!("{} {}", name, key),
+                }
+            }
+            return 0;
+        }
+        Some("--") => {
+            args.next();
+        }
+        _ => (),
+    }
+
+    let mut status = 0;
+    for arg in args {
+        let mut parts = arg.splitn(2, '=');
+        let key = parts.next().unwrap();
+        if !is_name(key) {
+            eprintln!("rush: {}: `{}': not a valid identifier", name, arg);
+            status = 1;
+            continue;
+        }
+        let mut shell = shell.borrow_mut();
+        if let Some(value) = parts.next() {
+            if let Err(e) = shell.set_var(String::from(key), String::from(value)) {
+                eprintln!("rush: {}: {}", name, e);
+                status = 1;
+                continue;
+            }
+        }
+        give(shell.vars.entry(String::from(key)).or_default());
+    }
+    status
+}
+
+// Drops the first n positional parameters, 1 by default
+pub fn shift(args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> i32 {
+    let n = match args.first().map_or(Ok(1), |x| x.parse::<usize>()) {
+        Ok(n) => n,
+        Err(_) => {
+            eprintln!("rush: shift: {}: bad number", args[0]);
+            return 1;
+        }
+    };
+    let mut shell = shell.borrow_mut();
+    if n > shell.get_positional().len() {
+        eprintln!("rush: shift: can't shift that many");
+        return 1;
+    }
+    let pos = shell.get_positional()[n..].to_vec();
+    shell.set_pos(pos);
+    0
+}
+
+// Hands out one option at a time from the positional parameters, or the arguments
+// after `name` if there are any. `OPTIND` is the next argument to look at, and
+// `OPTARG` is the option's argument when it takes one (a `:` after it in `optstring`).
+// A `:` at the very start of `optstring` means errors are left to the script: instead
+// of a message, `name` is `?` for an unknown option and `:` for a missing argument,
+// with the option itself in `OPTARG`.
+pub fn getopts(args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> i32 {
+    if args.len() < 2 {
+        eprintln!("rush: getopts: usage: getopts optstring name [arg ...]");
+        return 2;
     }
+    let (optstring, name) = (&args[0], &args[1]);
+    if !is_name(name) {
+        eprintln!("rush: getopts: `{}': not a valid identifier", name);
+        return 2;
+    }
+    let mut shell = shell.borrow_mut();
+    let params = if args.len() > 2 { args[2..].to_vec() } else { shell.get_positional().to_vec() };
+    let silent = optstring.starts_with(':');
+
+    let mut optind = shell.get_var("OPTIND").and_then(|i| i.parse::<usize>().ok()).unwrap_or(1).max(1);
+    // Picks up in the middle of an argument only if `OPTIND` is still where we left it
+    let mut offset = if shell.opt_place.0 == optind { shell.opt_place.1 } else { 1 };
+    let arg: Vec<char> = params.get(optind - 1).map_or_else(Vec::new, |arg| arg.chars().collect());
+
+    let mut found = None;
+    if offset == 1 && arg == ['-', '-'] {
+        optind += 1;
+    } else if arg.len() > offset && arg[0] == '-' {
+        let c = arg[offset];
+        offset += 1;
+        let mut optarg = None;
+        // Whether it's an option at all, and if so, whether it takes an argument
+        let spec = &optstring[silent as usize..];
+        let opt = match spec.find(c) {
+            Some(i) if c != ':' => Some(spec[i + c.len_utf8()..].starts_with(':')),
+            _ => None,
+        };
+        let result = match opt {
+            None => {
+                if silent {
+                    optarg = Some(c.to_string());
+                } else {
+                    eprintln!("rush: getopts: illegal option -- {}", c);
+                }
+                '?'
+            }
+            Some(true) if offset < arg.len() => {
+                optarg = Some(arg[offset..].iter().collect());
+                offset = arg.len();
+                c
+            }
+            Some(true) => match params.get(optind) {
+                Some(next) => {
+                    optarg = Some(next.clone());
+                    optind += 1;
+                    c
+                }
+                None if silent => {
+                    optarg = Some(c.to_string());
+                    ':'
+                }
+                None => {
+                    eprintln!("rush: getopts: option requires an argument -- {}", c);
+                    '?'
+                }
+            },
+            Some(false) => c,
+        };
+        if offset >= arg.len() {
+            optind += 1;
+            offset = 1;
+        }
+        found = Some((result, optarg));
+    }
+
+    let (result, optarg) = found.clone().unwrap_or(('?', None));
+    let set = shell.set_var(String::from("OPTIND"), optind.to_string())
+        .and_then(|_| shell.set_var(name.clone(), result.to_string()))
+        .and_then(|_| match optarg {
+            Some(optarg) => shell.set_var(String::from("OPTARG"), optarg),
+            None => shell.unset_var("OPTARG"),
+        });
+    if let Err(e) = set {
+        eprintln!("rush: getopts: {}", e);
+        return 2;
+    }
+    // After setting `OPTIND`, which would have us start over otherwise
+    shell.opt_place = (optind, offset);
+    if found.is_some() { 0 } else { 1 }
+}
+
+// Variables by default, or functions with `-f`
+pub fn unset(args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> i32 {
+    let mut args = args.into_iter().peekable();
+    let mut functions = false;
+    while let Some(arg) = args.peek() {
+        match arg.as_str() {
+            "-f" => functions = true,
+            "-v" => functions = false,
+            "--" => {
+                args.next();
+                break;
+            }
+            _ => break,
+        }
+        args.next();
+    }
+
+    let mut status = 0;
+    let mut shell = shell.borrow_mut();
+    for name in args {
+        if functions {
+            shell.functions.remove(&name);
+        } else if let Err(e) = shell.unset_var(&name) {
+            eprintln!("rush: unset: {}", e);
+            status = 1;
+        }
+    }
+    status
+}
+
+// The arguments are put back together and run as if they'd been typed in
+pub fn eval(args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> i32 {
+    let source = format!("{}\n", args.join(" "));
+    run_input(Box::new(Cursor::new(source.into_bytes())), shell)
+}
+
+// Runs a file in this shell instead of a new one. Without a slash, it's looked for
+// on `PATH`, like a command would be.
+pub fn dot(args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> i32 {
+    let name = match args.first() {
+        Some(name) => name,
+        None => {
+            eprintln!("rush: .: filename argument required");
+            return 2;
+        }
+    };
+    let path = shell.borrow().find_in_path(name);
+    let error = match path.map(File::open) {
+        Some(Ok(file)) => {
+            shell.borrow_mut().dots += 1;
+            let status = run_input(Box::new(BufReader::new(file)), shell);
+            let mut shell = shell.borrow_mut();
+            shell.dots -= 1;
+            // `return` goes as far as the end of the file, and no further
+            if let Some(Skip::Return(n)) = shell.skip {
+                shell.skip = None;
+                return n;
+            }
+            return status;
+        }
+        Some(Err(e)) => e.to_string(),
+        None => String::from("not found"),
+    };
+    eprintln!("rush: .: {}: {}", name, error);
+    // A script that's missing a piece of itself can't go on
+    if !shell.borrow().is_interactive() {
+        exit_program(2);
+    }
+    2
+}
+
+fn run_input(input: Box<dyn BufRead>, shell: &Rc<RefCell<Shell>>) -> i32 {
+    shell.borrow_mut().push_input(input);
+    let status = Runner::new(Rc::clone(shell)).run();
+    shell.borrow_mut().pop_input();
+    status
 }
 
-// Set very versetaile normally, this is just positional parameters for now
-pub fn set(args: Vec<String>, shell: &Rc<RefCell<Shell>>) -> bool {
-    shell.borrow_mut().set_pos(args);
-    true
+// Single quotes keep everything as it is, except for more single quotes
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
 }
 
+#[cfg(test)]
+mod builtins_tests {
+    use super::{dot, eval, export, getopts, readonly, shift, unset};
+    use crate::helpers::Shell;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::{env, fs, process};
+
+    // Arguments as plain words, split on spaces
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    fn var(shell: &Rc<RefCell<Shell>>, key: &str) -> Option<String> {
+        shell.borrow().get_var(key)
+    }
+
+    // What `getopts` gave back, and the variables it set
+    fn next_opt(line: &str, shell: &Rc<RefCell<Shell>>) -> (i32, String, Option<String>, String) {
+        let status = getopts(args(line), shell);
+        (status, var(shell, "o").unwrap(), var(shell, "OPTARG"), var(shell, "OPTIND").unwrap())
+    }
+
+    #[test]
+    fn test_getopts_bunched() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        shell.borrow_mut().set_pos(args("-ab file"));
+        assert_eq!((0, String::from("a"), None, String::from("1")), next_opt("ab o", &shell));
+        assert_eq!((0, String::from("b"), None, String::from("2")), next_opt("ab o", &shell));
+        assert_eq!((1, String::from("?"), None, String::from("2")), next_opt("ab o", &shell));
+
+        // Setting `OPTIND` starts over, even partway through `-ab`
+        shell.borrow_mut().set_var(String::from("OPTIND"), String::from("1")).unwrap();
+        next_opt("ab o", &shell);
+        shell.borrow_mut().set_pos(args("-cd"));
+        shell.borrow_mut().set_var(String::from("OPTIND"), String::from("1")).unwrap();
+        assert_eq!((0, String::from("c"), None, String::from("1")), next_opt("cd o", &shell));
+    }
+
+    #[test]
+    fn test_getopts_arguments() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let line = "c:a o -cval -c val2 -a -- -a";
+        let val = Some(String::from("val"));
+        assert_eq!((0, String::from("c"), val, String::from("2")), next_opt(line, &shell));
+        let val = Some(String::from("val2"));
+        assert_eq!((0, String::from("c"), val, String::from("4")), next_opt(line, &shell));
+        assert_eq!((0, String::from("a"), None, String::from("5")), next_opt(line, &shell));
+        // `--` is used up, and that's the end
+        assert_eq!((1, String::from("?"), None, String::from("6")), next_opt(line, &shell));
+    }
+
+    #[test]
+    fn test_getopts_silent() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let line = ":ab: o -z -b";
+        let z = Some(String::from("z"));
+        assert_eq!((0, String::from("?"), z, String::from("2")), next_opt(line, &shell));
+        let b = Some(String::from("b"));
+        assert_eq!((0, String::from(":"), b, String::from("3")), next_opt(line, &shell));
+        // Without the `:`, it's `?` either way and `OPTARG` is left unset
+        shell.borrow_mut().set_var(String::from("OPTIND"), String::from("1")).unwrap();
+        assert_eq!((0, String::from("?"), None, String::from("2")), next_opt("ab: o -z -b", &shell));
+        assert_eq!((0, String::from("?"), None, String::from("3")), next_opt("ab: o -z -b", &shell));
+    }
+
+    #[test]
+    fn test_shift() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        shell.borrow_mut().set_pos(args("a b c"));
+        assert_eq!(0, shift(vec![], &shell));
+        assert_eq!(args("b c"), shell.borrow().get_positional());
+        assert_eq!(1, shift(args("3"), &shell));
+        assert_eq!(1, shift(args("x"), &shell));
+        assert_eq!(args("b c"), shell.borrow().get_positional());
+        assert_eq!(0, shift(args("2"), &shell));
+        assert!(shell.borrow().get_positional().is_empty());
+    }
+
+    #[test]
+    fn test_declare() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        assert_eq!(0, readonly(args("fixed=1"), &shell));
+        assert_eq!(1, unset(args("fixed"), &shell));
+        assert_eq!(1, export(args("fixed=2"), &shell));
+        assert_eq!(Some(String::from("1")), var(&shell, "fixed"));
+
+        assert_eq!(0, export(args("a=1 b"), &shell));
+        assert!(shell.borrow().vars["a"].exported && shell.borrow().vars["b"].exported);
+        assert_eq!(None, var(&shell, "b"));
+        assert_eq!(1, export(args("1a=2"), &shell));
+        assert_eq!(0, unset(args("a"), &shell));
+        assert_eq!(None, var(&shell, "a"));
+    }
+
+    #[test]
+    fn test_eval_dot() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        assert_eq!(0, eval(args("x=1; y=$x$x"), &shell));
+        assert_eq!(Some(String::from("11")), var(&shell, "y"));
+
+        // `return` ends the file, and only the file
+        let file = env::temp_dir().join(format!("rush-dot-{}", process::id()));
+        fs::write(&file, "z=3\nreturn 5\nz=4\n").unwrap();
+        assert_eq!(5, dot(vec![file.to_string_lossy().into_owned()], &shell));
+        assert_eq!(Some(String::from("3")), var(&shell, "z"));
+        assert!(shell.borrow().skip.is_none());
+        fs::remove_file(file).unwrap();
+    }
+}
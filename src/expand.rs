@@ -0,0 +1,352 @@
+// Expansion happens right before a command runs rather than when it's parsed,
+// so a loop body or a function sees the shell as it is each time around
+use crate::arith;
+use crate::helpers::Shell;
+use crate::lexer::{Action, Expand::{self, *}};
+use crate::pattern;
+use crate::runner::Runner;
+use nix::unistd::User;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Collects the fields a word expands to, each one both as is and as a pattern
+#[derive(Default)]
+struct Fields {
+    done: Vec<(String, String)>,
+    word: String,
+    pattern: String,
+    // Whether there's a field here at all, even if it's empty, like `""`
+    started: bool,
+}
+
+impl Fields {
+    fn push(&mut self, s: &str, quoted: bool) {
+        self.word.push_str(s);
+        if quoted {
+            self.pattern.push_str(&pattern::escape(s));
+        } else {
+            self.pattern.push_str(s);
+        }
+        self.started |= quoted || !s.is_empty();
+    }
+
+    fn finish(&mut self, forced: bool) {
+        if self.started || forced {
+            let word = std::mem::take(&mut self.word);
+            let pattern = std::mem::take(&mut self.pattern);
+            self.done.push((word, pattern));
+        }
+        self.started = false;
+    }
+
+    // Field splitting, for the results of unquoted expansions:
+    // https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_05
+    // Runs of IFS whitespace separate fields, while every other IFS character ends
+    // one, even if it's empty. Whitespace around those doesn't count for anything.
+    fn split(&mut self, s: &str, ifs: &str) {
+        let mut after_space = false;
+        for c in s.chars() {
+            if !ifs.contains(c) {
+                let mut buf = [0; 4];
+                self.push(c.encode_utf8(&mut buf), false);
+                after_space = false;
+            } else if c.is_whitespace() {
+                if self.started {
+                    self.finish(false);
+                    after_space = true;
+                }
+            } else {
+                if !after_space {
+                    self.finish(true);
+                }
+                after_space = false;
+            }
+        }
+    }
+}
+
+pub struct Expander {
+    shell: Rc<RefCell<Shell>>,
+    // Status of the last command substitution, which is what a command
+    // with no name to run ends up with
+    pub sub_status: Option<i32>,
+}
+
+impl Expander {
+    pub fn new(shell: Rc<RefCell<Shell>>) -> Expander {
+        Expander {
+            shell,
+            sub_status: None,
+        }
+    }
+
+    // Turns words into however many fields they end up being. Unquoted expansions get
+    // split up by IFS, and one that comes out empty doesn't make a field at all.
+    // Then each pattern turns into the paths it matches, if there are any.
+    pub fn fields(&mut self, words: &[Vec<Expand>]) -> Result<Vec<String>, String> {
+        let ifs = self.shell.borrow().get_var("IFS").unwrap_or_else(|| String::from(" \t\n"));
+        let mut fields = Fields::default();
+        for word in words {
+            for part in word {
+                match part {
+                    Literal(s) => fields.push(s, false),
+                    Quoted(parts) if parts.is_empty() => fields.push("", true),
+                    Quoted(parts) => {
+                        for part in parts {
+                            match part {
+                                // Every parameter is a field of its own, and no parameters means
+                                // no fields, quotes or not
+                                Var(name) if name == "@" => {
+                                    let params = self.shell.borrow().get_positional().to_vec();
+                                    for (i, param) in params.iter().enumerate() {
+                                        if i > 0 {
+                                            fields.finish(true);
+                                        }
+                                        fields.push(param, true);
+                                    }
+                                }
+                                part => {
+                                    let s = self.part(part)?;
+                                    fields.push(&s, true);
+                                }
+                            }
+                        }
+                    }
+                    Tilde(_) => {
+                        let s = self.part(part)?;
+                        fields.push(&s, false);
+                    }
+                    // Unquoted, both of these split up each parameter on its own
+                    Var(name) if name == "@" || name == "*" => {
+                        let params = self.shell.borrow().get_positional().to_vec();
+                        for (i, param) in params.iter().enumerate() {
+                            if i > 0 {
+                                fields.finish(false);
+                            }
+                            fields.split(param, &ifs);
+                        }
+                    }
+                    part => {
+                        let s = self.part(part)?;
+                        fields.split(&s, &ifs);
+                    }
+                }
+            }
+            fields.finish(false);
+        }
+
+        let noglob = self.shell.borrow().options.noglob;
+        let mut result = Vec::new();
+        for (word, pattern) in fields.done {
+            if !noglob && pattern::has_wildcards(&pattern) {
+                let paths = pattern::glob(&pattern);
+                if !paths.is_empty() {
+                    result.extend(paths);
+                    continue;
+                }
+            }
+            result.push(word);
+        }
+        Ok(result)
+    }
+
+    // Quoted parts of a pattern only match themselves, while anything else,
+    // including the results of expansions, keeps its special meaning
+    pub fn pattern(&mut self, word: &[Expand]) -> Result<String, String> {
+        let mut pattern = String::new();
+        for part in word {
+            match part {
+                Quoted(parts) => pattern.push_str(&pattern::escape(&self.word(parts)?)),
+                part => pattern.push_str(&self.part(part)?),
+            }
+        }
+        Ok(pattern)
+    }
+
+    // A word that stays one word no matter what, like an assignment or a file to redirect to
+    pub fn word(&mut self, word: &[Expand]) -> Result<String, String> {
+        let mut phrase = String::new();
+        for part in word {
+            phrase.push_str(&self.part(part)?);
+        }
+        Ok(phrase)
+    }
+
+    fn part(&mut self, part: &Expand) -> Result<String, String> {
+        match part {
+            Literal(s) => Ok(s.clone()),
+            Quoted(word) => self.word(word),
+            Tilde(word) => {
+                let s = self.word(word)?;
+                let mut phrase = String::new();
+                if s.is_empty() || s.starts_with('/') {
+                    phrase.push_str(&self.shell.borrow().get_var("HOME").unwrap_or_default());
+                    phrase.push_str(&s);
+                } else {
+                    let mut strings = s.splitn(2, '/');
+                    let name = strings.next().unwrap();
+                    if let Some(user) = User::from_name(name).unwrap() {
+                        phrase.push_str(user.dir.as_os_str().to_str().unwrap());
+                        if let Some(path) = strings.next() {
+                            phrase.push('/');
+                            phrase.push_str(path);
+                        }
+                    } else {
+                        phrase.push('~');
+                        phrase.push_str(name);
+                    }
+                }
+                Ok(phrase)
+            }
+            Var(s) => self.get_var(s).map(Option::unwrap_or_default),
+            Brace(key, action, word) => self.brace(key, action, word),
+            Sub(source) => {
+                // Only trailing newlines go, anything else is up to field splitting
+                let output = Runner::new(Rc::clone(&self.shell)).capture(source);
+                self.sub_status = Some(self.shell.borrow().status);
+                Ok(output)
+            }
+            Arith(word) => {
+                let expr = self.word(word)?;
+                match arith::evaluate(&expr, &self.shell) {
+                    Ok(n) => Ok(n.to_string()),
+                    Err(e) => Err(format!("rush: {}: {}", expr.trim(), e)),
+                }
+            }
+        }
+    }
+
+    // With `set -u`, anything but `$@` and `$*` has to be set to be used
+    fn get_var(&self, key: &str) -> Result<Option<String>, String> {
+        let shell = self.shell.borrow();
+        match shell.get_var(key) {
+            None if shell.options.nounset && key != "@" && key != "*" => {
+                Err(format!("rush: {}: parameter not set", key))
+            }
+            val => Ok(val),
+        }
+    }
+
+    // What the brace does expansion does:
+    // https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_02
+    fn brace(&mut self, key: &str, action: &Action, word: &[Expand]) -> Result<String, String> {
+        let val = match action {
+            Action::RmSmallestSuffix | Action::RmLargestSuffix | Action::RmSmallestPrefix
+                | Action::RmLargestPrefix | Action::StringLength => self.get_var(key)?,
+            // These are all about what to do when it isn't set, so `set -u` doesn't apply
+            _ => self.shell.borrow().get_var(key),
+        };
+        match *action {
+            Action::UseDefault(null) => match val {
+                Some(s) if !(s.is_empty() && null) => Ok(s),
+                _ => self.word(word),
+            },
+            Action::AssignDefault(null) => match val {
+                Some(s) if !(s.is_empty() && null) => Ok(s),
+                _ => {
+                    let expanded = self.word(word)?;
+                    self.shell.borrow_mut().set_var(String::from(key), expanded.clone())
+                        .map_err(|e| format!("rush: {}", e))?;
+                    Ok(expanded)
+                }
+            },
+            Action::IndicateError(null) => match val {
+                Some(s) if !(s.is_empty() && null) => Ok(s),
+                val => {
+                    let message = self.word(word)?;
+                    if !message.is_empty() {
+                        Err(format!("rush: {}: {}", key, message))
+                    } else if val.is_some() {
+                        Err(format!("rush: {}: parameter null", key))
+                    } else {
+                        Err(format!("rush: {}: parameter not set", key))
+                    }
+                }
+            },
+            Action::UseAlternate(null) => match val {
+                Some(s) if !(s.is_empty() && null) => self.word(word),
+                _ => Ok(String::new()),
+            },
+            Action::RmSmallestSuffix | Action::RmLargestSuffix => {
+                let val = val.unwrap_or_default();
+                let pattern = self.pattern(word)?;
+                let largest = *action == Action::RmLargestSuffix;
+                Ok(String::from(pattern::remove_suffix(&pattern, &val, largest)))
+            }
+            Action::RmSmallestPrefix | Action::RmLargestPrefix => {
+                let val = val.unwrap_or_default();
+                let pattern = self.pattern(word)?;
+                let largest = *action == Action::RmLargestPrefix;
+                Ok(String::from(pattern::remove_prefix(&pattern, &val, largest)))
+            }
+            Action::StringLength => Ok(val.unwrap_or_default().chars().count().to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod expand_tests {
+    use super::Expander;
+    use crate::helpers::Shell;
+    use crate::lexer::{Lexer, Token::Word};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Expands every word on the line, as if they were the words of a command
+    fn expand(line: &str, shell: &Rc<RefCell<Shell>>) -> Vec<String> {
+        let words: Vec<_> = Lexer::new(line, Rc::clone(shell))
+            .map(|token| match token {
+                Word(word) => word,
+                token => panic!("expected a word but found {:?}", token),
+            })
+            .collect();
+        Expander::new(Rc::clone(shell)).fields(&words).unwrap()
+    }
+
+    #[test]
+    fn test_split() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        shell.borrow_mut().set_var(String::from("split"), String::from(" a  b ")).unwrap();
+        shell.borrow_mut().set_var(String::from("empty"), String::new()).unwrap();
+        let expected = vec!["echo", "x", "a", "b", " a  b ", ""];
+        assert_eq!(expected, expand("echo x$split\"$split\" $empty \"$empty\"", &shell));
+    }
+
+    #[test]
+    fn test_positional() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        shell.borrow_mut().set_pos(vec![String::from("a b"), String::new(), String::from("c")]);
+        let expected = vec!["echo", "-a b", "", "c-", "a", "b", "c", "a b  c"];
+        assert_eq!(expected, expand("echo \"-$@-\" $* \"$*\"", &shell));
+        shell.borrow_mut().set_pos(Vec::new());
+        assert_eq!(vec!["echo"], expand("echo \"$@\"", &shell));
+    }
+
+    #[test]
+    fn test_brace() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        shell.borrow_mut().set_var(String::from("file"), String::from("a.tar.gz")).unwrap();
+        let expected = vec!["a", "a.tar", "8", "x", "x"];
+        assert_eq!(expected, expand("${file%%.*} ${file%.gz} ${#file} ${unset:-x} ${unset:=x}", &shell));
+        assert_eq!(Some(String::from("x")), shell.borrow().get_var("unset"));
+        let mut expander = Expander::new(Rc::clone(&shell));
+        let word = match Lexer::new("${nope?}", Rc::clone(&shell)).next() {
+            Some(Word(word)) => word,
+            _ => unreachable!(),
+        };
+        assert_eq!(Err(String::from("rush: nope: parameter not set")), expander.word(&word));
+    }
+
+    #[test]
+    fn test_nounset() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        shell.borrow_mut().options.nounset = true;
+        assert_eq!(vec!["x", "0"], expand("${nope-x} ${#@} $@", &shell));
+        let mut expander = Expander::new(Rc::clone(&shell));
+        let word = match Lexer::new("$nope", Rc::clone(&shell)).next() {
+            Some(Word(word)) => word,
+            _ => unreachable!(),
+        };
+        assert_eq!(Err(String::from("rush: nope: parameter not set")), expander.word(&word));
+    }
+}
@@ -1,10 +1,17 @@
-use nix::unistd::Uid;
-use os_pipe::{dup_stderr, dup_stdin, dup_stdout, PipeReader, PipeWriter};
+use crate::lexer::Expand;
+use crate::parser::Cmd;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::unistd::{Pid, Uid};
+use os_pipe::{dup_stderr, dup_stdin, dup_stdout, pipe, PipeReader, PipeWriter};
 use std::collections::HashMap;
 use std::env;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::process::{Stdio, self};
+use std::rc::Rc;
 
 // My own, less nasty version of BufRead::lines().
 // Returns an Option rather Option<Result>,
@@ -32,43 +39,198 @@ impl<B: BufRead> Iterator for Lines<B> {
     }
 }
 
+// Set by `break` and `continue` to tell the loops they're in to stop what they're doing
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Skip {
+    Break(u32),
+    Continue(u32),
+    Return(i32),
+}
+
+// Whether something can be used as the name of a variable or function
+pub fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Whatever `set` has turned on
+#[derive(Debug, Default)]
+pub struct Options {
+    // -e, exit as soon as something fails
+    pub errexit: bool,
+    // -u, using a variable that isn't set is an error
+    pub nounset: bool,
+    // -x, write out each command before it's run
+    pub xtrace: bool,
+    // -f, no pathname expansion
+    pub noglob: bool,
+    // -n, read commands without running them
+    pub noexec: bool,
+    // -v, write out input as it's read
+    pub verbose: bool,
+    // -C, `>` won't write over files that are already there
+    pub noclobber: bool,
+    // A pipeline fails if anything in it does, not just the last command
+    pub pipefail: bool,
+}
+
+// Every option's name, in the order `set -o` lists them
+const OPTION_NAMES: &[&str] = &[
+    "errexit",
+    "noclobber",
+    "noexec",
+    "noglob",
+    "nounset",
+    "pipefail",
+    "verbose",
+    "xtrace",
+];
+
+// The options that have a letter too. `pipefail` only goes by its name.
+const OPTION_LETTERS: &[(char, &str)] = &[
+    ('e', "errexit"),
+    ('C', "noclobber"),
+    ('n', "noexec"),
+    ('f', "noglob"),
+    ('u', "nounset"),
+    ('v', "verbose"),
+    ('x', "xtrace"),
+];
+
+impl Options {
+    fn get(&self, name: &str) -> bool {
+        match name {
+            "errexit" => self.errexit,
+            "nounset" => self.nounset,
+            "xtrace" => self.xtrace,
+            "noglob" => self.noglob,
+            "noexec" => self.noexec,
+            "verbose" => self.verbose,
+            "noclobber" => self.noclobber,
+            "pipefail" => self.pipefail,
+            _ => false,
+        }
+    }
+
+    // Turns an option on or off by its letter, if there is such an option
+    pub fn set(&mut self, flag: char, on: bool) -> bool {
+        match OPTION_LETTERS.iter().find(|(f, _)| *f == flag) {
+            Some((_, name)) => self.set_named(name, on),
+            None => false,
+        }
+    }
+
+    pub fn set_named(&mut self, name: &str, on: bool) -> bool {
+        match name {
+            "errexit" => self.errexit = on,
+            "nounset" => self.nounset = on,
+            "xtrace" => self.xtrace = on,
+            "noglob" => self.noglob = on,
+            "noexec" => self.noexec = on,
+            "verbose" => self.verbose = on,
+            "noclobber" => self.noclobber = on,
+            "pipefail" => self.pipefail = on,
+            _ => return false,
+        }
+        true
+    }
+
+    // Each option's name and whether it's on, for `set -o` to list
+    pub fn named(&self) -> Vec<(&'static str, bool)> {
+        OPTION_NAMES.iter().map(|name| (*name, self.get(name))).collect()
+    }
+
+    // The letters of every option that's on, for `$-`
+    pub fn letters(&self) -> String {
+        OPTION_LETTERS.iter().filter(|(_, name)| self.get(name)).map(|(flag, _)| *flag).collect()
+    }
+}
+
+// A variable and its attributes. Exporting or making a variable read-only doesn't
+// need a value, so it might not have one yet.
+#[derive(Debug, Default, Clone)]
+pub struct Var {
+    pub value: Option<String>,
+    pub exported: bool,
+    pub readonly: bool,
+}
+
 pub struct Shell {
-    lines: Lines<Box<dyn BufRead>>,
+    // Where commands come from. `.` and `eval` read from their own input until it runs
+    // out, then it's back to whatever was reading before.
+    sources: Vec<Lines<Box<dyn BufRead>>>,
     interactive: bool,
     positional: Vec<String>,
     name: String,
-    pub vars: HashMap<String, String>,
+    pub vars: HashMap<String, Var>,
+    pub loops: u32,
+    pub skip: Option<Skip>,
+    pub functions: HashMap<String, Rc<Cmd>>,
+    // How many function calls deep we are, and how many `.` files, which `return` also ends
+    pub calls: u32,
+    pub dots: u32,
+    // Background commands that might still be running, and the last one started for `$!`
+    pub jobs: Vec<Pid>,
+    pub last_job: Option<Pid>,
+    // Exit status of the last command, for `$?`
+    pub status: i32,
+    pub options: Options,
+    // How many conditions deep we are, where `set -e` doesn't apply
+    pub conditions: u32,
+    // Where `getopts` left off: the `OPTIND` it set, and how far into that argument it got,
+    // for options that come bunched up like `-ab`
+    pub opt_place: (usize, usize),
 }
 
 impl Shell {
     pub fn new(file: Option<String>) -> Shell {
-        let (lines, interactive, name): (Lines<Box<dyn BufRead>>, bool, String) =
-            if let Some(filename) = file {
-                (
-                    Lines::new(Box::new(BufReader::new(fs::File::open(&filename).unwrap()))),
-                    false,
-                    filename,
-                )
-            } else {
-                (
-                    Lines::new(Box::new(BufReader::new(io::stdin()))),
-                    true,
-                    String::from("rush"),
-                )
-            };
+        if let Some(filename) = file {
+            let file = BufReader::new(fs::File::open(&filename).unwrap());
+            Shell::from_input(Box::new(file), false, filename)
+        } else {
+            Shell::from_input(Box::new(BufReader::new(io::stdin())), true, String::from("rush"))
+        }
+    }
+
+    // Commands come from wherever `input` does, and `name` is what `$0` says
+    pub fn from_input(input: Box<dyn BufRead>, interactive: bool, name: String) -> Shell {
         Shell {
-            lines,
+            sources: vec![Lines::new(input)],
             interactive,
             positional: Vec::new(),
             name,
-            vars: HashMap::new(),
+            vars: Shell::environment(),
+            loops: 0,
+            skip: None,
+            functions: HashMap::new(),
+            calls: 0,
+            dots: 0,
+            jobs: Vec::new(),
+            last_job: None,
+            status: 0,
+            options: Options::default(),
+            conditions: 0,
+            opt_place: (1, 1),
         }
     }
 
+    // Everything we were started with is exported right back to our own children
+    fn environment() -> HashMap<String, Var> {
+        env::vars_os()
+            .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+            .map(|(key, value)| (key, Var { value: Some(value), exported: true, readonly: false }))
+            .collect()
+    }
+
     pub fn get_pos(&self, n: u32) -> Option<&String> {
         self.positional.get((n - 1) as usize)
     }
 
+    pub fn get_positional(&self) -> &[String] {
+        &self.positional
+    }
+
     pub fn set_pos(&mut self, pos: Vec<String>) {
         self.positional = pos;
     }
@@ -77,19 +239,46 @@ impl Shell {
         self.interactive
     }
 
+    pub fn push_input(&mut self, input: Box<dyn BufRead>) {
+        self.sources.push(Lines::new(input));
+    }
+
+    pub fn pop_input(&mut self) {
+        self.sources.pop();
+    }
+
+    // Only the terminal itself gets a prompt, not what's being read for `.` or `eval`
+    fn prompting(&self) -> bool {
+        self.is_interactive() && self.sources.len() == 1
+    }
+
     pub fn next_prompt(&mut self, prompt: &str) -> Option<String> {
-        if self.is_interactive() {
+        if self.prompting() {
             print!("{}", prompt);
             io::stdout().flush().unwrap();
         }
-        self.lines.next()
+        self.read_line()
+    }
+
+    // `set -v` echoes everything as it's read in, with a newline even if the
+    // last line didn't have one
+    fn read_line(&mut self) -> Option<String> {
+        let line = self.sources.last_mut()?.next();
+        if self.options.verbose {
+            if let Some(line) = &line {
+                if line.ends_with('\n') {
+                    eprint!("{}", line);
+                } else {
+                    eprintln!("{}", line);
+                }
+            }
+        }
+        line
     }
 
     // Not super satisfied with this as it is returning a String when it could be a 
     // reference, but this also allows handling stuff like $@ right here, as that would need to be 
     // stitched together here and thus it would own the value.
-    // Also, env:: calls in Rust seem to return ownership rather than references, which is
-    // nasty.
     pub fn get_var(&self, key: &str) -> Option<String> {
         if let Ok(num) = key.parse::<u32>() {
             if num == 0 {
@@ -99,23 +288,78 @@ impl Shell {
             }
         } else {
             match key {
-                "@" | "*" => Some(self.positional.join(" ")), // these are technically more complicated but it works for now
+                // Where these turn into several fields, that's taken care of by the parser
+                "@" => Some(self.positional.join(" ")),
+                "*" => {
+                    let ifs = self.get_var("IFS").unwrap_or_else(|| String::from(" "));
+                    Some(self.positional.join(&ifs.chars().take(1).collect::<String>()))
+                }
                 "#" => Some(self.positional.len().to_string()), 
+                "-" => {
+                    let mut flags = self.options.letters();
+                    if self.interactive {
+                        flags.push('i');
+                    }
+                    Some(flags)
+                }
                 "$" => Some(process::id().to_string()), 
-                _ => self
-                    .vars
-                    .get(key)
-                    .map_or(env::var(key).ok(), |s| Some(String::from(s))),
+                "?" => Some(self.status.to_string()),
+                "!" => self.last_job.map(|pid| pid.to_string()),
+                _ => self.vars.get(key).and_then(|var| var.value.clone()),
             }
         }
     }
 
-    pub fn set_var(&mut self, key: String, val: String) {
-        if env::var_os(&key).is_some() {
-            env::set_var(key, val);
-        } else {
-            self.vars.insert(key, val);
+    // Whoever's setting the variable gets to say where the error came from
+    pub fn set_var(&mut self, key: String, val: String) -> Result<(), String> {
+        if self.vars.get(&key).is_some_and(|var| var.readonly) {
+            return Err(format!("{}: is read only", key));
+        }
+        // Setting `OPTIND` at all means `getopts` starts over
+        if key == "OPTIND" {
+            self.opt_place = (1, 1);
+        }
+        self.vars.entry(key).or_default().value = Some(val);
+        Ok(())
+    }
+
+    pub fn unset_var(&mut self, key: &str) -> Result<(), String> {
+        if self.vars.get(key).is_some_and(|var| var.readonly) {
+            return Err(format!("{}: is read only", key));
+        }
+        if key == "OPTIND" {
+            self.opt_place = (1, 1);
+        }
+        self.vars.remove(key);
+        Ok(())
+    }
+
+    // What a command we run gets for an environment
+    pub fn exported(&self) -> Vec<(String, String)> {
+        self.vars
+            .iter()
+            .filter(|(_, var)| var.exported)
+            .filter_map(|(key, var)| Some((key.clone(), var.value.clone()?)))
+            .collect()
+    }
+
+    // Where a command is on `PATH`, unless it has a slash to say where it is already
+    pub fn find_in_path(&self, name: &str) -> Option<PathBuf> {
+        if name.contains('/') {
+            return Some(PathBuf::from(name));
         }
+        self.get_var("PATH")
+            .unwrap_or_default()
+            .split(':')
+            .map(|dir| Path::new(if dir.is_empty() { "." } else { dir }).join(name))
+            .find(|path| path.is_file())
+    }
+
+    // Gets rid of any background commands that are done, so they don't stick around as zombies
+    pub fn reap_jobs(&mut self) {
+        self.jobs.retain(|pid| {
+            matches!(waitpid(*pid, Some(WaitPidFlag::WNOHANG)), Ok(WaitStatus::StillAlive))
+        });
     }
 }
 
@@ -123,7 +367,8 @@ impl Iterator for Shell {
     type Item = String;
 
     fn next(&mut self) -> Option<String> {
-        if self.is_interactive() {
+        self.reap_jobs();
+        if self.prompting() {
             if Uid::current().is_root() {
                 print!("#> ");
             } else {
@@ -131,7 +376,7 @@ impl Iterator for Shell {
             }
             io::stdout().flush().unwrap();
         }
-        self.lines.next()
+        self.read_line()
     }
 }
 
@@ -146,8 +391,19 @@ pub enum Fd {
     PipeOut(PipeWriter),
     PipeIn(PipeReader),
     FileName(String),
+    // `<`, which only ever reads
+    FileNameRead(String),
     FileNameAppend(String),
+    // `>` with `set -C` on, which won't write over a regular file that's already there
+    FileNameNew(String),
     RawFile(File),
+    HereDoc(String),
+    // `>&3`, a descriptor the shell already has open
+    Dup(RawFd),
+    // `>&-`
+    Closed,
+    // A word that turns into one of the above once it's expanded, right before it's used
+    Unexpanded(fn(String) -> Fd, Vec<Expand>),
 }
 
 impl PartialEq for Fd {
@@ -166,15 +422,42 @@ impl Fd {
             Fd::PipeOut(_) => "PipeOut",
             Fd::PipeIn(_) => "PipeIn",
             Fd::FileName(_) => "FileName",
+            Fd::FileNameRead(_) => "FileNameRead",
             Fd::FileNameAppend(_) => "FileNameAppend",
+            Fd::FileNameNew(_) => "FileNameNew",
             Fd::RawFile(_) => "RawFile", // Not completely accurate, but I think fine for now
+            Fd::HereDoc(_) => "HereDoc",
+            Fd::Dup(_) => "Dup",
+            Fd::Closed => "Closed",
+            Fd::Unexpanded(_, _) => "Unexpanded",
         }
     }
 
-    // Gets an stdin - all same here as stdout, except that a file is opened, not created
+    // Commands can be run more than once, so they get a copy of their Fds to use up
+    pub fn try_clone(&self) -> Fd {
+        match self {
+            Fd::Stdin => Fd::Stdin,
+            Fd::Stdout => Fd::Stdout,
+            Fd::Stderr => Fd::Stderr,
+            Fd::Inherit => Fd::Inherit,
+            Fd::PipeOut(writer) => Fd::PipeOut(writer.try_clone().unwrap()),
+            Fd::PipeIn(reader) => Fd::PipeIn(reader.try_clone().unwrap()),
+            Fd::FileName(name) => Fd::FileName(name.clone()),
+            Fd::FileNameRead(name) => Fd::FileNameRead(name.clone()),
+            Fd::FileNameAppend(name) => Fd::FileNameAppend(name.clone()),
+            Fd::FileNameNew(name) => Fd::FileNameNew(name.clone()),
+            Fd::RawFile(file) => Fd::RawFile(file.try_clone().unwrap()),
+            Fd::HereDoc(doc) => Fd::HereDoc(doc.clone()),
+            Fd::Dup(fd) => Fd::Dup(*fd),
+            Fd::Closed => Fd::Closed,
+            Fd::Unexpanded(fd, word) => Fd::Unexpanded(*fd, word.clone()),
+        }
+    }
+
+    // Gets an stdin - all same here as stdout, except for what's only ever read from
     pub fn get_stdin(&mut self) -> Option<Stdio> {
         match self {
-            Fd::FileName(name) => match File::open(&name) {
+            Fd::FileNameRead(name) => match File::open(&name) {
                 Ok(file) => {
                     *self = Fd::RawFile(file.try_clone().unwrap());
                     Some(Stdio::from(file))
@@ -184,6 +467,12 @@ impl Fd {
                     None
                 }
             },
+            Fd::HereDoc(doc) => {
+                let (reader, mut writer) = pipe().unwrap();
+                writer.write_all(doc.as_bytes()).unwrap();
+                *self = Fd::PipeIn(reader.try_clone().unwrap());
+                Some(Stdio::from(reader))
+            }
             _ => self.get_stdout(),
         }
     }
@@ -196,10 +485,15 @@ impl Fd {
             Fd::Stdin => Some(Stdio::from(dup_stdin().unwrap())),
             Fd::Stdout => Some(Stdio::from(dup_stdout().unwrap())),
             Fd::Stderr => Some(Stdio::from(dup_stderr().unwrap())),
-            Fd::Inherit => Some(Stdio::inherit()),
+            // Whoever runs the command closes it first, and then there's nothing to inherit
+            Fd::Inherit | Fd::Closed => Some(Stdio::inherit()),
             Fd::PipeOut(writer) => Some(Stdio::from(writer.try_clone().unwrap())),
             Fd::PipeIn(reader) => Some(Stdio::from(reader.try_clone().unwrap())),
             Fd::RawFile(file) => Some(Stdio::from(file.try_clone().unwrap())),
+            Fd::HereDoc(_) | Fd::FileNameRead(_) => self.get_stdin(),
+            Fd::Unexpanded(_, _) => unreachable!("redirections are expanded before they're used"),
+            // The new descriptor is ours alone, so it's fine to hand it off
+            Fd::Dup(_) => self.get_raw(false).map(|fd| Stdio::from(unsafe { File::from_raw_fd(fd) })),
             Fd::FileName(name) => match File::create(&name) {
                 Ok(file) => {
                     *self = Fd::RawFile(file.try_clone().unwrap());
@@ -210,6 +504,22 @@ impl Fd {
                     None
                 }
             },
+            Fd::FileNameNew(name) => {
+                if fs::metadata(&name).is_ok_and(|m| m.is_file()) {
+                    eprintln!("rush: {}: cannot overwrite existing file", name);
+                    return None;
+                }
+                match File::create(&name) {
+                    Ok(file) => {
+                        *self = Fd::RawFile(file.try_clone().unwrap());
+                        Some(Stdio::from(file))
+                    }
+                    Err(e) => {
+                        eprintln!("rush: {}: {}", name, e);
+                        None
+                    }
+                }
+            }
             Fd::FileNameAppend(name) => {
                 match OpenOptions::new().append(true).create(true).open(&name) {
                     Ok(file) => {
@@ -228,4 +538,40 @@ impl Fd {
     pub fn get_stderr(&mut self) -> Option<Stdio> {
         self.get_stdout()
     }
+
+    // The same, but as a descriptor of our own, for the shell to put wherever it wants
+    pub fn get_raw(&mut self, input: bool) -> Option<RawFd> {
+        match self {
+            Fd::Stdin => copy_high(0).ok(),
+            Fd::Stdout => copy_high(1).ok(),
+            Fd::Stderr => copy_high(2).ok(),
+            Fd::Inherit | Fd::Closed => None,
+            Fd::PipeOut(writer) => copy_high(writer.as_raw_fd()).ok(),
+            Fd::PipeIn(reader) => copy_high(reader.as_raw_fd()).ok(),
+            Fd::RawFile(file) => copy_high(file.as_raw_fd()).ok(),
+            Fd::Dup(fd) => match copy_high(*fd) {
+                Ok(new) => Some(new),
+                Err(_) => {
+                    eprintln!("rush: {}: {}", fd, io::Error::last_os_error());
+                    None
+                }
+            },
+            Fd::Unexpanded(_, _) => unreachable!("redirections are expanded before they're used"),
+            // Opening a file or here-doc leaves behind something we can take a copy of.
+            // It went to the lowest number that's free, which could be the very one it's
+            // meant for, so only a copy up high is kept.
+            _ => {
+                let opened = if input { self.get_stdin() } else { self.get_stdout() };
+                let raw = opened.and_then(|_| self.get_raw(input))?;
+                *self = Fd::RawFile(unsafe { File::from_raw_fd(raw) });
+                self.get_raw(input)
+            }
+        }
+    }
+}
+
+// The shell's own copies of descriptors start at 10, out of the way of anything a script
+// might ask for, and aren't passed on to what it runs
+fn copy_high(fd: RawFd) -> nix::Result<RawFd> {
+    fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(10))
 }
@@ -17,22 +17,28 @@ pub enum Token {
     Assign(String, Vec<Expand>),
     Op(Op),
     Punct(Punct),
+    Reserved(Reserved),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Expand {
     Literal(String),
     Var(String),
     Tilde(Vec<Expand>),
     Brace(String, Action, Vec<Expand>),
-    Sub(Vec<Expand>),
+    // Command substitutions keep their source, to be parsed and run when expanded
+    Sub(String),
+    Arith(Vec<Expand>),
+    // Anything in here came from quotes (or a backslash), and so has
+    // lost any special meaning
+    Quoted(Vec<Expand>),
 }
 
 // What the brace does expansion does:
 // https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_02
 // If true test for unset or null, if false, only unset
 // For prefix/suffix, true for largest false for smallest
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Action {
     UseDefault(bool),
     AssignDefault(bool),
@@ -49,7 +55,7 @@ impl Expand {
     pub fn get_name(self) -> String {
         match self {
             Literal(s) | Var(s) | Brace(s, _, _) => s,
-            Tilde(_) | Sub(_) => panic!("you shouldn't be doing this"),
+            Tilde(_) | Sub(_) | Arith(_) | Quoted(_) => panic!("you shouldn't be doing this"),
         }
     }
 }
@@ -72,6 +78,68 @@ pub enum Punct {
     LParen,
     RParen,
     Semicolon,
+    DoubleSemicolon,
+    Newline,
+}
+
+// Reserved words, only recognized where a command name would otherwise be
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Reserved {
+    If,
+    Then,
+    Elif,
+    Else,
+    Fi,
+    While,
+    Until,
+    Do,
+    Done,
+    For,
+    Case,
+    Esac,
+    LBrace,
+    RBrace,
+}
+
+impl Reserved {
+    fn from_word(word: &str) -> Option<Reserved> {
+        match word {
+            "if" => Some(Reserved::If),
+            "then" => Some(Reserved::Then),
+            "elif" => Some(Reserved::Elif),
+            "else" => Some(Reserved::Else),
+            "fi" => Some(Reserved::Fi),
+            "while" => Some(Reserved::While),
+            "until" => Some(Reserved::Until),
+            "do" => Some(Reserved::Do),
+            "done" => Some(Reserved::Done),
+            "for" => Some(Reserved::For),
+            "case" => Some(Reserved::Case),
+            "esac" => Some(Reserved::Esac),
+            "{" => Some(Reserved::LBrace),
+            "}" => Some(Reserved::RBrace),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Reserved::If => "if",
+            Reserved::Then => "then",
+            Reserved::Elif => "elif",
+            Reserved::Else => "else",
+            Reserved::Fi => "fi",
+            Reserved::While => "while",
+            Reserved::Until => "until",
+            Reserved::Do => "do",
+            Reserved::Done => "done",
+            Reserved::For => "for",
+            Reserved::Case => "case",
+            Reserved::Esac => "esac",
+            Reserved::LBrace => "{",
+            Reserved::RBrace => "}",
+        }
+    }
 }
 
 // This representation makes it's functions very nice and easy,
@@ -86,12 +154,121 @@ fn invalid_var(c: char) -> bool {
 }
 
 fn is_token_split(c: char) -> bool {
-    matches!(c, '&' | '!' | '|' | '<' | '>' | '=') || c.is_whitespace()
+    matches!(c, '&' | '|' | '<' | '>' | '=' | ';' | '(' | ')') || c.is_whitespace()
+}
+
+// What ends a word for the purpose of spotting reserved words
+fn is_delimiter(c: char) -> bool {
+    matches!(c, '&' | '|' | '<' | '>' | ';' | '(' | ')') || c.is_whitespace()
+}
+
+// The body of a here-document whose delimiter isn't quoted, which gets expanded
+// as if it was in double quotes
+pub fn heredoc(doc: &str, shell: Rc<RefCell<Shell>>) -> Result<Vec<Expand>, String> {
+    Lexer::new(doc, shell).read_until(false, false, false, true, Box::new(|_| false))
+}
+
+// Where a `case` in a command substitution is at
+#[derive(PartialEq)]
+enum CasePart {
+    Subject,
+    Pattern,
+    Body,
+}
+
+// Follows along with any `case` in a command substitution, just well enough to tell
+// which parentheses belong to its patterns rather than to the substitution
+struct Cases {
+    // Each `case` we're in, with how many parentheses deep it started
+    open: Vec<(CasePart, u32)>,
+    word: String,
+    // Whether a command could start here, which is the only place `case` means anything
+    command_start: bool,
+    // Whether the current pattern has anything in it yet. A `(` before it is optional.
+    pattern_started: bool,
+}
+
+impl Cases {
+    fn new() -> Cases {
+        Cases { open: Vec::new(), word: String::new(), command_start: true, pattern_started: false }
+    }
+
+    fn in_pattern(&self, depth: u32) -> bool {
+        matches!(self.open.last(), Some((CasePart::Pattern, start)) if *start == depth)
+    }
+
+    // Takes in each unquoted character, with everything before it. Gives back whether
+    // it's a parenthesis around a pattern, which doesn't count towards the depth.
+    fn see(&mut self, c: char, source: &str, depth: u32) -> bool {
+        if c.is_alphanumeric() || c == '_' {
+            self.word.push(c);
+            self.pattern_started = true;
+            return false;
+        }
+        if !self.word.is_empty() {
+            let word = std::mem::take(&mut self.word);
+            self.end_word(&word, depth);
+        }
+        match c {
+            ' ' | '\t' => (),
+            ';' if source.ends_with(';') => {
+                if let Some((part @ CasePart::Body, _)) = self.open.last_mut() {
+                    *part = CasePart::Pattern;
+                    self.pattern_started = false;
+                }
+                self.command_start = true;
+            }
+            ';' | '&' | '|' | '\n' | '{' => self.command_start = true,
+            '(' if self.in_pattern(depth) && !self.pattern_started => {
+                self.pattern_started = true;
+                return true;
+            }
+            '(' => self.command_start = true,
+            ')' if self.in_pattern(depth) => {
+                if let Some((part, _)) = self.open.last_mut() {
+                    *part = CasePart::Body;
+                }
+                self.command_start = true;
+                return true;
+            }
+            _ => {
+                self.command_start = false;
+                self.pattern_started = true;
+            }
+        }
+        false
+    }
+
+    fn end_word(&mut self, word: &str, depth: u32) {
+        let part = self.open.last().map(|(part, _)| part);
+        match word {
+            "case" if self.command_start => {
+                self.open.push((CasePart::Subject, depth));
+                self.command_start = false;
+            }
+            "in" if part == Some(&CasePart::Subject) => {
+                self.open.last_mut().unwrap().0 = CasePart::Pattern;
+                self.pattern_started = false;
+            }
+            "esac" if part == Some(&CasePart::Pattern) || (part == Some(&CasePart::Body) && self.command_start) => {
+                self.open.pop();
+                self.command_start = false;
+            }
+            "if" | "then" | "else" | "elif" | "while" | "until" | "do" => self.command_start = true,
+            _ => self.command_start = false,
+        }
+    }
 }
 
 pub struct Lexer {
     shell: Rc<RefCell<Shell>>,
     line: Peekable<IntoIter<char>>,
+    // Whether the current line ended in a newline, so there might be more to read
+    more: bool,
+    // Whether the next word would be a command name, where reserved words count
+    cmd_pos: bool,
+    // Whether the next word is the target of a redirection
+    redirect: bool,
 }
 
 impl Lexer {
@@ -99,11 +276,15 @@ impl Lexer {
         Lexer {
             shell,
             line: line.chars().collect::<Vec<_>>().into_iter().peekable(),
+            more: line.ends_with('\n'),
+            cmd_pos: true,
+            redirect: false,
         }
     }
 
     fn advance_line(&mut self) -> Result<(), String> {
         if let Some(s) = self.shell.borrow_mut().next_prompt("> ") {
+            self.more = s.ends_with('\n');
             self.line = s.chars().collect::<Vec<_>>().into_iter().peekable();
             Ok(())
         } else {
@@ -119,19 +300,45 @@ impl Lexer {
         self.line.next()
     }
 
+    // Newlines are tokens of their own, so they're not skipped here
     fn skip_whitespace(&mut self) {
         let mut next = self.peek_char();
-        while next.is_some() && next.unwrap().is_whitespace() {
+        while next.is_some() && *next.unwrap() != '\n' && next.unwrap().is_whitespace() {
             self.next_char();
             next = self.peek_char();
         }
     }
 
+    // Looks at the upcoming word without consuming it
+    fn peek_word(&self) -> String {
+        self.line.clone().take_while(|c| !is_delimiter(*c)).collect()
+    }
+
+    // If the upcoming word is a plain unquoted reserved word, returns it
+    fn peek_reserved(&self) -> Option<Reserved> {
+        Reserved::from_word(&self.peek_word())
+    }
+
+    // Only succeeds when the upcoming word is a bare number directly followed by
+    // a redirection, as in `2>&1`
+    fn peek_io_number(&self) -> Option<u32> {
+        let mut digits = String::new();
+        for c in self.line.clone() {
+            match c {
+                '0'..='9' => digits.push(c),
+                '<' | '>' => return digits.parse().ok(),
+                _ => break,
+            }
+        }
+        None
+    }
+
     fn read_until(
         &mut self,
         consume: bool,
         keep_going: bool,
         split_on_space: bool,
+        quoted: bool,
         break_cond: Box<dyn Fn(char) -> bool>,
     ) -> Result<Vec<Expand>, String> {
         let mut expandables = Vec::new();
@@ -144,7 +351,20 @@ impl Lexer {
                     self.next_char();
                     match self.next_char() {
                         Some('\n') => self.advance_line()?,
-                        Some(c) => cur_word.push(c),
+                        // Inside double quotes, a backslash only escapes a few characters
+                        Some(c) if quoted => {
+                            if !matches!(c, '$' | '`' | '"' | '\\') {
+                                cur_word.push('\\');
+                            }
+                            cur_word.push(c);
+                        }
+                        Some(c) => {
+                            if !cur_word.is_empty() {
+                                expandables.push(Literal(cur_word));
+                                cur_word = String::new();
+                            }
+                            expandables.push(Quoted(vec![Literal(c.to_string())]));
+                        }
                         None => (),
                     }
                 }
@@ -187,9 +407,24 @@ impl Lexer {
                             }
 
                             self.next_char();
-                            let param = self.read_raw_until(invalid_var)?;
+                            // `${#}` on its own is just the number of parameters
+                            if self.peek_char() == Some(&'#') && !matches!(self.line.clone().nth(1), Some('}') | None) {
+                                self.next_char();
+                                let param = self.read_param()?;
+                                if self.next_char() != Some('}') {
+                                    return Err(String::from("bad substitution"));
+                                }
+                                expandables.push(Brace(param, Action::StringLength, Vec::new()));
+                                next = self.peek_char();
+                                continue;
+                            }
+                            let param = self.read_param()?;
+                            if param.is_empty() {
+                                return Err(String::from("bad substitution"));
+                            }
 
                             let action = match self.next_char() {
+                                Some('}') => None,
                                 Some(':') => get_action(true, self.next_char()),
                                 Some('%') => {
                                     if let Some('%') = self.peek_char() {
@@ -207,12 +442,14 @@ impl Lexer {
                                         Some(Action::RmSmallestPrefix)
                                     }
                                 }
-                                Some(' ') => return Err(String::from("bad substitution")),
-                                c => get_action(false, c),
+                                c => match get_action(false, c) {
+                                    Some(action) => Some(action),
+                                    None => return Err(String::from("bad substitution")),
+                                },
                             };
 
                             if let Some(a) = action {
-                                let word = self.read_until(true, true, false, Box::new(|c| c == '}'))?;
+                                let word = self.read_until(true, true, false, quoted, Box::new(|c| c == '}'))?;
                                 expandables.push(Brace(param, a, word));
                             } else {
                                 expandables.push(Var(param));
@@ -220,7 +457,12 @@ impl Lexer {
                         }
                         Some('(') => {
                             self.next_char();
-                            expandables.push(Sub(self.read_until(true, true, true, Box::new(|c| c == ')'))?));
+                            if let Some('(') = self.peek_char() {
+                                self.next_char();
+                                expandables.push(Arith(self.read_arith()?));
+                            } else {
+                                expandables.push(Sub(self.read_sub()?));
+                            }
                         }
                         Some('$') => {
                             // '$$' command doesn't play nicely with the reading here,
@@ -228,42 +470,55 @@ impl Lexer {
                             self.next_char();
                             expandables.push(Var(String::from("$")));
                         }
+                        // Special parameters are only ever one character long
+                        Some(c) if matches!(c, '@' | '*' | '#' | '?' | '-' | '!') || c.is_ascii_digit() => {
+                            expandables.push(Var(self.next_char().unwrap().to_string()));
+                        }
                         _ => {
-                            expandables.push(Var(self.read_raw_until(invalid_var)?));
+                            let name = self.read_raw_until(|c| !(c.is_alphanumeric() || c == '_'))?;
+                            if name.is_empty() {
+                                cur_word.push('$');
+                            } else {
+                                expandables.push(Var(name));
+                            }
                         }
                     }
                 }
                 Some('`') => {
-                    // How often are backticks actually used for subshells?
-                    // I really don't want to have to implement nested backtick subshells...
+                    if !cur_word.is_empty() {
+                        expandables.push(Literal(cur_word));
+                        cur_word = String::new();
+                    }
                     self.next_char();
-                    expandables.push(Sub(self.read_until(true, true, true, Box::new(|c| c == '`'))?));
+                    expandables.push(Sub(self.read_backticks()?));
                 }
-                Some('~') => {
+                Some('~') if !quoted => {
                     if !cur_word.is_empty() {
                         expandables.push(Literal(cur_word));
                         cur_word = String::new();
                     }
                     self.next_char();
 
-                    let tilde = self.read_until(false, false, false, Box::new(invalid_var))?;
+                    // It can't go past the end of the word either
+                    let split = |c| invalid_var(c) || is_token_split(c);
+                    let tilde = self.read_until(false, false, false, false, Box::new(split))?;
                     expandables.push(Tilde(tilde));
                 }
-                Some('"') => {
+                Some('"') if !quoted => {
                     if !cur_word.is_empty() {
                         expandables.push(Literal(cur_word));
                         cur_word = String::new();
                     }
                     self.next_char();
 
-                    let mut result = self.read_until(true, true, false, Box::new(|c| c == '"'))?;
-                    if result.is_empty() {
-                        expandables.push(Literal(String::new()));
-                    } else {
-                        expandables.append(&mut result);
-                    }
+                    let result = self.read_until(true, true, false, true, Box::new(|c| c == '"'))?;
+                    expandables.push(Quoted(result));
                 }
-                Some('\'') => {
+                Some('\'') if !quoted => {
+                    if !cur_word.is_empty() {
+                        expandables.push(Literal(cur_word));
+                        cur_word = String::new();
+                    }
                     self.next_char();
                     let mut phrase = String::new();
                     loop {
@@ -273,7 +528,7 @@ impl Lexer {
                             None => self.advance_line()?,
                         }
                     }
-                    expandables.push(Literal(phrase));
+                    expandables.push(Quoted(vec![Literal(phrase)]));
                 }
                 Some(_) => cur_word.push(self.next_char().unwrap()),
                 None => {
@@ -294,6 +549,110 @@ impl Lexer {
 
     // You can accomplish this same thing with just the function above and some matching/unwrapping,
     // but I think this is cleaner
+    // Reads everything up to the `))` that matches the opening one, which can have
+    // parentheses of its own inside. What's in there is treated like it's in double quotes.
+    fn read_arith(&mut self) -> Result<Vec<Expand>, String> {
+        let mut expr = String::new();
+        let mut depth = 0;
+        loop {
+            match self.next_char() {
+                Some(')') if depth == 0 => {
+                    if let Some(')') = self.next_char() {
+                        break;
+                    }
+                    return Err(String::from("rush: expected `))` but found none"));
+                }
+                Some(c) => {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        _ => (),
+                    }
+                    expr.push(c);
+                }
+                None => self.advance_line()?,
+            }
+        }
+        let mut lexer = Lexer::new(&expr, Rc::clone(&self.shell));
+        lexer.read_until(false, false, false, true, Box::new(|_| false))
+    }
+
+    // Reads the source of a `$(...)` up to the parenthesis that closes it. Quotes and
+    // backslashes are skipped over so whatever's in them doesn't count, and so is the
+    // `)` after each `case` pattern. It's all parsed for real once it's run.
+    fn read_sub(&mut self) -> Result<String, String> {
+        let mut source = String::new();
+        let mut depth = 0;
+        let mut quote = None;
+        let mut cases = Cases::new();
+        loop {
+            let c = match self.next_char() {
+                Some(c) => c,
+                None => {
+                    self.advance_line()?;
+                    continue;
+                }
+            };
+            let pattern = quote.is_none() && cases.see(c, &source, depth);
+            match c {
+                '\\' if quote != Some('\'') => {
+                    source.push(c);
+                    match self.next_char() {
+                        Some(c) => source.push(c),
+                        None => continue,
+                    }
+                    continue;
+                }
+                '\'' | '"' if quote.is_none() => quote = Some(c),
+                c if quote == Some(c) => quote = None,
+                '(' if quote.is_none() && !pattern => depth += 1,
+                ')' if quote.is_none() && !pattern => {
+                    if depth == 0 {
+                        break;
+                    }
+                    depth -= 1;
+                }
+                _ => (),
+            }
+            source.push(c);
+        }
+        Ok(source)
+    }
+
+    // The old style of command substitution. A backslash only keeps its meaning
+    // before another backslash, a backtick, or a `$`.
+    fn read_backticks(&mut self) -> Result<String, String> {
+        let mut source = String::new();
+        loop {
+            match self.next_char() {
+                Some('`') => break,
+                Some('\\') => match self.next_char() {
+                    Some(c) if matches!(c, '\\' | '`' | '$') => source.push(c),
+                    Some(c) => {
+                        source.push('\\');
+                        source.push(c);
+                    }
+                    None => source.push('\\'),
+                },
+                Some(c) => source.push(c),
+                None => self.advance_line()?,
+            }
+        }
+        Ok(source)
+    }
+
+    // What goes inside of braces: a name, a number, or one of the special parameters
+    fn read_param(&mut self) -> Result<String, String> {
+        match self.peek_char().copied() {
+            Some(c) if matches!(c, '@' | '*' | '#' | '?' | '-' | '!' | '$') => {
+                self.next_char();
+                Ok(c.to_string())
+            }
+            Some(c) if c.is_ascii_digit() => self.read_raw_until(|c| !c.is_ascii_digit()),
+            _ => self.read_raw_until(|c| !(c.is_alphanumeric() || c == '_')),
+        }
+    }
+
     fn read_raw_until<F>(&mut self, break_cond: F) -> Result<String, String>
     where
         F: Fn(char) -> bool,
@@ -316,10 +675,28 @@ impl Lexer {
         Ok(word)
     }
 
-    // Of course, I still haven't added everything I'll need to yet
+    // Keeps track of where we are in a command, as whether a word is reserved
+    // depends on it
     fn next_token(&mut self) -> Option<Token> {
+        let token = self.read_token();
+        match &token {
+            Some(Token::Op(Op::Less)) | Some(Token::Op(Op::More)) => self.redirect = true,
+            Some(Token::Op(Op::Ampersand)) | Some(Token::Op(Op::Pipe)) if self.redirect => (),
+            Some(Token::Word(_)) if self.redirect => self.redirect = false,
+            Some(Token::Word(_)) => self.cmd_pos = false,
+            Some(Token::Assign(_, _)) | Some(Token::Integer(_)) => (),
+            _ => {
+                self.cmd_pos = true;
+                self.redirect = false;
+            }
+        }
+        token
+    }
+
+    // Of course, I still haven't added everything I'll need to yet
+    fn read_token(&mut self) -> Option<Token> {
         self.skip_whitespace();
-        match self.peek_char() {
+        match self.peek_char().copied() {
             Some('|') => {
                 self.next_char();
                 if let Some('|') = self.peek_char() {
@@ -346,7 +723,9 @@ impl Lexer {
                 self.next_char();
                 Some(Token::Op(Op::Less))
             }
-            Some('!') => {
+            // Just like a reserved word, it has to be a word of its own where a
+            // command would start, so `[ ! -f x ]` and `[!a]*` are left alone
+            Some('!') if self.cmd_pos && !self.redirect && self.peek_word() == "!" => {
                 self.next_char();
                 Some(Token::Op(Op::Bang))
             }
@@ -358,9 +737,41 @@ impl Lexer {
                 self.next_char();
                 Some(Token::Punct(Punct::RParen))
             }
-            Some(_) => match self.read_until(false, false, false, Box::new(is_token_split)) {
+            Some(';') => {
+                self.next_char();
+                if let Some(';') = self.peek_char() {
+                    self.next_char();
+                    Some(Token::Punct(Punct::DoubleSemicolon))
+                } else {
+                    Some(Token::Punct(Punct::Semicolon))
+                }
+            }
+            Some('\n') => {
+                self.next_char();
+                Some(Token::Punct(Punct::Newline))
+            }
+            Some('#') => {
+                while !matches!(self.peek_char(), Some('\n') | None) {
+                    self.next_char();
+                }
+                self.read_token()
+            }
+            Some(_) if self.cmd_pos && !self.redirect && self.peek_reserved().is_some() => {
+                let reserved = self.peek_reserved().unwrap();
+                for _ in reserved.as_str().chars() {
+                    self.next_char();
+                }
+                Some(Token::Reserved(reserved))
+            }
+            Some(_) if self.peek_io_number().is_some() => {
+                let num = self.peek_io_number().unwrap();
+                while let Some('0'..='9') = self.peek_char() {
+                    self.next_char();
+                }
+                Some(Token::Integer(num))
+            }
+            Some(_) => match self.read_until(false, false, false, false, Box::new(is_token_split)) {
                 Ok(w) => {
-                    println!("The words I got: {:?}", w);
                     match &w[..] {
                         [Literal(s), ..]
                             if s.ends_with('=')
@@ -371,13 +782,6 @@ impl Lexer {
                             name.pop();
                             Some(Token::Assign(name, iter.collect()))
                         }
-                        [Literal(s)] => {
-                            if let Ok(num) = s.parse::<u32>() {
-                                Some(Token::Integer(num))
-                            } else {
-                                Some(Token::Word(w))
-                            }
-                        }
                         _ => Some(Token::Word(w)),
                     }
                 }
@@ -386,7 +790,14 @@ impl Lexer {
                     None
                 }
             },
-            None => None,
+            None => {
+                // Only reached once a construct is left open past the end of a line
+                if self.more && self.advance_line().is_ok() {
+                    self.read_token()
+                } else {
+                    None
+                }
+            }
         }
     }
 }
@@ -401,7 +812,7 @@ impl Iterator for Lexer {
 // TODO: More tests
 #[cfg(test)]
 mod lexer_tests {
-    use super::{Expand::*, Lexer, Op, Token::*};
+    use super::{heredoc, Action, Expand::*, Lexer, Op, Punct, Reserved, Token::*};
     use crate::helpers::Shell;
     use std::cell::RefCell;
     use std::rc::Rc;
@@ -421,4 +832,116 @@ mod lexer_tests {
             assert_eq!(*token, lexer.next().unwrap())
         }
     }
+
+    #[test]
+    fn test_reserved() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("if echo then; then fi=1 \"fi\"; fi", Rc::clone(&shell));
+        let expected = vec![
+            Reserved(Reserved::If),
+            Word(vec![Literal(String::from("echo"))]),
+            Word(vec![Literal(String::from("then"))]),
+            Punct(Punct::Semicolon),
+            Reserved(Reserved::Then),
+            Assign(String::from("fi"), vec![Literal(String::from("1"))]),
+            Word(vec![Quoted(vec![Literal(String::from("fi"))])]),
+            Punct(Punct::Semicolon),
+            Reserved(Reserved::Fi),
+        ];
+        assert_eq!(expected, lexer.collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_bang() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("! [ ! -f x ] | ls [!a].rs", Rc::clone(&shell));
+        let expected = vec![
+            Op(Op::Bang),
+            Word(vec![Literal(String::from("["))]),
+            Word(vec![Literal(String::from("!"))]),
+            Word(vec![Literal(String::from("-f"))]),
+            Word(vec![Literal(String::from("x"))]),
+            Word(vec![Literal(String::from("]"))]),
+            Op(Op::Pipe),
+            Word(vec![Literal(String::from("ls"))]),
+            Word(vec![Literal(String::from("[!a].rs"))]),
+        ];
+        assert_eq!(expected, lexer.collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_arith() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("echo $(( (1 + $x) * 2 ))x", Rc::clone(&shell));
+        let expected = vec![
+            Word(vec![Literal(String::from("echo"))]),
+            Word(vec![
+                Arith(vec![
+                    Literal(String::from(" (1 + ")),
+                    Var(String::from("x")),
+                    Literal(String::from(") * 2 ")),
+                ]),
+                Literal(String::from("x")),
+            ]),
+        ];
+        assert_eq!(expected, lexer.collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_brace() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("${#x} ${#} ${x%%.*} ${1#'*'}", Rc::clone(&shell));
+        let expected = vec![
+            Word(vec![Brace(String::from("x"), Action::StringLength, vec![])]),
+            Word(vec![Var(String::from("#"))]),
+            Word(vec![Brace(String::from("x"), Action::RmLargestSuffix, vec![Literal(String::from(".*"))])]),
+            Word(vec![Brace(
+                String::from("1"),
+                Action::RmSmallestPrefix,
+                vec![Quoted(vec![Literal(String::from("*"))])],
+            )]),
+        ];
+        assert_eq!(expected, lexer.collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_tilde() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("echo ~; echo ~/x;(", Rc::clone(&shell));
+        let expected = vec![
+            Word(vec![Literal(String::from("echo"))]),
+            Word(vec![Tilde(vec![])]),
+            Punct(Punct::Semicolon),
+            Word(vec![Literal(String::from("echo"))]),
+            Word(vec![Tilde(vec![Literal(String::from("/x"))])]),
+            Punct(Punct::Semicolon),
+            Punct(Punct::LParen),
+        ];
+        assert_eq!(expected, lexer.collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_heredoc() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let expected = vec![
+            Literal(String::from("'hi' \"")),
+            Var(String::from("x")),
+            Literal(String::from("\" $x \\n\n")),
+        ];
+        assert_eq!(Ok(expected), heredoc("'hi' \"$x\" \\$x \\n\n", shell));
+    }
+
+    #[test]
+    fn test_sub() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let source = "$(case x in (x) echo ')';; y) (echo $(echo y));; esac)x $(echo case)";
+        let expected = vec![
+            Word(vec![
+                Sub(String::from("case x in (x) echo ')';; y) (echo $(echo y));; esac")),
+                Literal(String::from("x")),
+            ]),
+            Word(vec![Sub(String::from("echo case"))]),
+        ];
+        assert_eq!(expected, Lexer::new(source, shell).collect::<Vec<_>>())
+    }
 }
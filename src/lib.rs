@@ -1,5 +1,8 @@
 pub mod lexer;
 pub mod parser;
 pub mod runner;
+pub mod expand;
 pub mod helpers;
 pub mod builtins;
+pub mod pattern;
+pub mod arith;
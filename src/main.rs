@@ -1,39 +1,100 @@
-use rush::lexer::Lexer;
-use rush::parser::Parser;
 use rush::runner::Runner;
-use rush::helpers::Shell;
+use rush::helpers::{Options, Shell};
+use nix::unistd::isatty;
 use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor};
+use std::process;
 use std::cell::RefCell;
 use std::rc::Rc;
 
-fn main() {
-    let mut args = env::args();
-    args.next();
-
-    let shell = Rc::new(RefCell::new(Shell::new(args.last())));
-    let runner = Runner::new(Rc::clone(&shell));
+// What the command line asked for, aside from the options that `set` knows about
+#[derive(Default)]
+struct Invocation {
+    command: bool,
+    stdin: bool,
+    interactive: bool,
+}
 
-    loop {
-        let input = shell.borrow_mut().next();
-        if let Some(line) = input {
-            let lexer = Lexer::new(&line, Rc::clone(&shell));
-            let mut parser = Parser::new(lexer, Rc::clone(&shell));
-            match parser.get() {
-                Ok(command) => {
-                    #[cfg(debug_assertions)] // Only include when not built with `--release` flag
-                    println!("\u{001b}[34m{:#?}\u{001b}[0m", command);
+fn usage(message: &str) -> ! {
+    eprintln!("rush: {}", message);
+    eprintln!("usage: rush [-is] [-Cefnuvx] [-o option] [script [arg ...]]");
+    eprintln!("       rush -c [-i] [-Cefnuvx] [-o option] command_string [name [arg ...]]");
+    process::exit(2);
+}
 
-                    runner.execute(command, false);
-                }
-                Err(e) => {
-                    eprintln!("{}", e);
-                }
+// https://pubs.opengroup.org/onlinepubs/9699919799/utilities/sh.html
+// Options come first, `-` turning one on and `+` turning it off, up until the
+// first thing that isn't one, or `--`. Whatever's left is for the script.
+fn parse_options(args: impl Iterator<Item = String>, options: &mut Options) -> (Invocation, Vec<String>) {
+    let mut invocation = Invocation::default();
+    let mut args = args.peekable();
+    while let Some(arg) = args.peek() {
+        if arg == "--" || arg == "-" {
+            args.next();
+            break;
+        }
+        let on = match arg.chars().next() {
+            Some('-') => true,
+            Some('+') => false,
+            _ => break,
+        };
+        let arg = args.next().unwrap();
+        for c in arg.chars().skip(1) {
+            match c {
+                'c' if on => invocation.command = true,
+                's' if on => invocation.stdin = true,
+                'i' if on => invocation.interactive = true,
+                'o' => match args.next() {
+                    Some(name) if options.set_named(&name, on) => (),
+                    Some(name) => usage(&format!("{}: invalid option name", name)),
+                    None => usage("-o: option requires an argument"),
+                },
+                c if options.set(c, on) => (),
+                c => usage(&format!("{}{}: invalid option", if on { '-' } else { '+' }, c)),
             }
-        } else {
-            if shell.borrow().is_interactive() {
-                println!();
+        }
+    }
+    (invocation, args.collect())
+}
+
+fn main() {
+    let mut options = Options::default();
+    let (invocation, args) = parse_options(env::args().skip(1), &mut options);
+    let mut args = args.into_iter();
+
+    let (input, name, interactive): (Box<dyn BufRead>, String, bool) = if invocation.command {
+        let source = match args.next() {
+            Some(source) => source,
+            None => usage("-c: option requires an argument"),
+        };
+        let name = args.next().unwrap_or_else(|| String::from("rush"));
+        (Box::new(Cursor::new(source.into_bytes())), name, false)
+    } else if invocation.stdin || args.len() == 0 {
+        // Reading from a terminal is what makes a shell interactive, unless it's told otherwise
+        let terminal = isatty(0).unwrap_or(false) && isatty(2).unwrap_or(false);
+        (Box::new(BufReader::new(io::stdin())), String::from("rush"), terminal)
+    } else {
+        let name = args.next().unwrap();
+        match File::open(&name) {
+            Ok(file) => (Box::new(BufReader::new(file)), name, false),
+            Err(e) => {
+                eprintln!("rush: {}: {}", name, e);
+                process::exit(127);
             }
-            break;
         }
+    };
+
+    let mut shell = Shell::from_input(input, interactive || invocation.interactive, name);
+    shell.set_pos(args.collect());
+    shell.options = options;
+    let shell = Rc::new(RefCell::new(shell));
+    let runner = Runner::new(Rc::clone(&shell));
+
+    runner.run();
+    if shell.borrow().is_interactive() {
+        println!();
     }
+    let status = shell.borrow().status;
+    process::exit(status);
 }
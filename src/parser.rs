@@ -1,20 +1,13 @@
-use crate::helpers::{Fd, Shell};
-use crate::lexer::Token::{self, *};
+use crate::helpers::{is_name, Fd, Shell};
+use crate::lexer::{self, Token::{self, *}};
 use crate::lexer::{
-    Action,
     Expand::{self, *},
-    Op,
+    Op, Punct, Reserved,
 };
-use nix::unistd::User;
-use os_pipe::pipe;
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::env;
-use std::io::Write;
 use std::iter::Peekable;
-use std::process::exit;
+use std::os::unix::io::RawFd;
 use std::rc::Rc;
-use crate::runner::Runner;
 
 #[derive(Debug, PartialEq)]
 pub enum Cmd {
@@ -23,14 +16,35 @@ pub enum Cmd {
     And(Box<Cmd>, Box<Cmd>),
     Or(Box<Cmd>, Box<Cmd>),
     Not(Box<Cmd>),
+    Sequence(Box<Cmd>, Box<Cmd>),
+    // Condition, what to do if it succeeds, and what to do otherwise.
+    // `elif` is just another `If` in the last spot.
+    If(Box<Cmd>, Box<Cmd>, Option<Box<Cmd>>),
+    While(Box<Cmd>, Box<Cmd>),
+    Until(Box<Cmd>, Box<Cmd>),
+    // The name to assign to, and the words to assign. No words means
+    // the positional parameters.
+    For(String, Option<Vec<Vec<Expand>>>, Box<Cmd>),
+    // The word to match, and each set of patterns with what to run when one matches
+    Case(Vec<Expand>, Vec<(Vec<Vec<Expand>>, Cmd)>),
+    // A compound command with redirections of its own, like `{ ...; } > file`
+    Redirect(Box<Cmd>, Io),
+    // Function definitions. The body outlives the rest of the AST, so it's shared.
+    Function(String, Rc<Cmd>),
+    Subshell(Box<Cmd>),
+    // Runs in the background, with nothing waiting for it
+    Async(Box<Cmd>),
     Empty,
 }
 
 // Keeps track of io in one spot before it's put into a command
+#[derive(Debug, PartialEq)]
 pub struct Io {
-    stdin: Rc<RefCell<Fd>>,
-    stdout: Rc<RefCell<Fd>>,
-    stderr: Rc<RefCell<Fd>>,
+    pub stdin: Rc<RefCell<Fd>>,
+    pub stdout: Rc<RefCell<Fd>>,
+    pub stderr: Rc<RefCell<Fd>>,
+    // Anything past 2, like the 3 in `3>log`
+    pub others: Vec<(RawFd, Rc<RefCell<Fd>>)>,
 }
 
 impl Io {
@@ -39,6 +53,7 @@ impl Io {
             stdin: Rc::new(RefCell::new(Fd::Stdin)),
             stdout: Rc::new(RefCell::new(Fd::Stdout)),
             stderr: Rc::new(RefCell::new(Fd::Stderr)),
+            others: Vec::new(),
         }
     }
 
@@ -53,33 +68,80 @@ impl Io {
     fn set_stderr(&mut self, fd: Rc<RefCell<Fd>>) {
         self.stderr = fd;
     }
+
+    fn set_other(&mut self, n: RawFd, fd: Rc<RefCell<Fd>>) {
+        match self.others.iter_mut().find(|(other, _)| *other == n) {
+            Some((_, old)) => *old = fd,
+            None => self.others.push((n, fd)),
+        }
+    }
+
+    // The same command can be run many times over in a loop, so each run gets its own
+    // copy of the redirections to open files with. Fds shared through something like
+    // `2>&1` stay shared in the copy.
+    pub fn copy(&self) -> Io {
+        let mut fds = vec![&self.stdin, &self.stdout, &self.stderr];
+        fds.extend(self.others.iter().map(|(_, fd)| fd));
+        let mut copies: Vec<Rc<RefCell<Fd>>> = Vec::new();
+        for (i, fd) in fds.iter().enumerate() {
+            let copy = match fds[..i].iter().position(|old| Rc::ptr_eq(old, fd)) {
+                Some(j) => Rc::clone(&copies[j]),
+                None => Rc::new(RefCell::new(fd.borrow().try_clone())),
+            };
+            copies.push(copy);
+        }
+        let mut copies = copies.into_iter();
+        let stdin = copies.next().unwrap();
+        let stdout = copies.next().unwrap();
+        let stderr = copies.next().unwrap();
+        let others = self.others.iter().map(|(n, _)| *n).zip(copies).collect();
+        Io { stdin, stdout, stderr, others }
+    }
+
+    fn get(&self, n: RawFd) -> Option<&Rc<RefCell<Fd>>> {
+        match n {
+            0 => Some(&self.stdin),
+            1 => Some(&self.stdout),
+            2 => Some(&self.stderr),
+            _ => self.others.iter().find(|(other, _)| *other == n).map(|(_, fd)| fd),
+        }
+    }
 }
 
-// The most basic command - it, its arguments, and its redirections.
+// The most basic command - its words, assignments, and redirections.
+// Nothing's expanded until it runs, so the first word isn't necessarily the command.
+// Without any words, the assignments are for the shell itself.
 #[derive(Debug, PartialEq)]
 pub struct Simple {
-    pub cmd: String,
-    pub args: Vec<String>,
-    pub env: Option<HashMap<String, String>>,
+    pub words: Vec<Vec<Expand>>,
+    pub assigns: Vec<(String, Vec<Expand>)>,
     pub stdin: Rc<RefCell<Fd>>,
     pub stdout: Rc<RefCell<Fd>>,
     pub stderr: Rc<RefCell<Fd>>,
+    pub others: Vec<(RawFd, Rc<RefCell<Fd>>)>,
 }
 
 impl Simple {
-    fn new(cmd: String, args: Vec<String>, io: Io) -> Simple {
+    fn new(words: Vec<Vec<Expand>>, assigns: Vec<(String, Vec<Expand>)>, io: Io) -> Simple {
         Simple {
-            cmd,
-            args,
-            env: None,
+            words,
+            assigns,
             stdin: io.stdin,
             stdout: io.stdout,
             stderr: io.stderr,
+            others: io.others,
         }
     }
 
-    fn add_env(&mut self, map: HashMap<String, String>) {
-        self.env = Some(map);
+    // Each run gets its own copy of the redirections, like with `Io::copy`
+    pub fn copy_io(&self) -> Io {
+        let io = Io {
+            stdin: Rc::clone(&self.stdin),
+            stdout: Rc::clone(&self.stdout),
+            stderr: Rc::clone(&self.stderr),
+            others: self.others.clone(),
+        };
+        io.copy()
     }
 }
 
@@ -103,16 +165,61 @@ impl<I> Parser<I>
         }
     }
 
+    // Everything there is to parse, like the source of a command substitution
+    pub fn get_all(&mut self) -> Result<Cmd, String> {
+        let mut node = Cmd::Empty;
+        while self.lexer.peek().is_some() {
+            node = match (node, self.get()?) {
+                (node, Cmd::Empty) => node,
+                (Cmd::Empty, cmd) => cmd,
+                (node, cmd) => Cmd::Sequence(Box::new(node), Box::new(cmd)),
+            };
+        }
+        Ok(node)
+    }
+
+    // Gets a complete command, which is everything up until the end of the line.
+    // Anything that isn't finished by then keeps reading more lines.
     pub fn get(&mut self) -> Result<Cmd, String> {
-        self.get_and()
+        let mut node = Cmd::Empty;
+        loop {
+            match self.lexer.peek() {
+                None => break,
+                Some(Punct(Punct::Newline)) => {
+                    self.lexer.next();
+                    break;
+                }
+                _ => (),
+            }
+            let mut cmd = self.get_and()?;
+            match self.lexer.peek() {
+                Some(Punct(Punct::Semicolon)) => {
+                    self.lexer.next();
+                }
+                Some(Op(Op::Ampersand)) => {
+                    self.lexer.next();
+                    cmd = Cmd::Async(Box::new(cmd));
+                }
+                Some(Punct(Punct::Newline)) | None => (),
+                Some(Reserved(r)) => return Err(format!("rush: syntax error near unexpected `{}`", r.as_str())),
+                Some(_) => return Err(String::from("rush: syntax error near unexpected token")),
+            }
+            node = match node {
+                Cmd::Empty => cmd,
+                node => Cmd::Sequence(Box::new(node), Box::new(cmd)),
+            };
+        }
+        Ok(node)
     }
 
     pub fn get_and(&mut self) -> Result<Cmd, String> {
         let mut node = self.get_pipe()?;
         while let Some(Op(Op::And)) | Some(Op(Op::Or)) = self.lexer.peek() {
             if let Some(Op(Op::And)) = self.lexer.next() {
+                self.skip_newlines();
                 node = Cmd::And(Box::new(node), Box::new(self.get_pipe()?));
             } else {
+                self.skip_newlines();
                 node = Cmd::Or(Box::new(node), Box::new(self.get_pipe()?));
             }
         }
@@ -120,265 +227,447 @@ impl<I> Parser<I>
     }
 
     pub fn get_pipe(&mut self) -> Result<Cmd, String> {
-        let mut node = self.get_simple()?;
+        if let Some(Op(Op::Bang)) = self.lexer.peek() {
+            self.lexer.next();
+            return Ok(Cmd::Not(Box::new(self.get_pipe()?)));
+        }
+        let mut node = self.get_command()?;
         while let Some(Op(Op::Pipe)) = self.lexer.peek() {
             self.lexer.next();
-            node = Cmd::Pipeline(Box::new(node), Box::new(self.get_simple()?));
+            self.skip_newlines();
+            node = Cmd::Pipeline(Box::new(node), Box::new(self.get_command()?));
         }
         Ok(node)
     }
 
-    pub fn get_simple(&mut self) -> Result<Cmd, String> {
-        if let Some(Op(Op::Bang)) = self.lexer.peek() {
+    pub fn get_command(&mut self) -> Result<Cmd, String> {
+        let compound = match self.lexer.peek() {
+            Some(Reserved(Reserved::If)) => {
+                self.lexer.next();
+                self.get_if()
+            }
+            Some(Reserved(Reserved::While)) => {
+                self.lexer.next();
+                let (cond, body) = self.get_loop()?;
+                Ok(Cmd::While(Box::new(cond), Box::new(body)))
+            }
+            Some(Reserved(Reserved::Until)) => {
+                self.lexer.next();
+                let (cond, body) = self.get_loop()?;
+                Ok(Cmd::Until(Box::new(cond), Box::new(body)))
+            }
+            Some(Reserved(Reserved::For)) => {
+                self.lexer.next();
+                self.get_for()
+            }
+            Some(Reserved(Reserved::Case)) => {
+                self.lexer.next();
+                self.get_case()
+            }
+            // Braces only group commands, so there's nothing left of them afterwards
+            Some(Reserved(Reserved::LBrace)) => {
+                self.lexer.next();
+                let list = self.get_list()?;
+                self.expect(Reserved::RBrace)?;
+                Ok(list)
+            }
+            Some(Punct(Punct::LParen)) => {
+                self.lexer.next();
+                let list = self.get_list()?;
+                if let Some(Punct(Punct::RParen)) = self.lexer.next() {
+                    Ok(Cmd::Subshell(Box::new(list)))
+                } else {
+                    Err(String::from("rush: expected `)` but found none"))
+                }
+            }
+            Some(Reserved(r)) => Err(format!("rush: syntax error near unexpected `{}`", r.as_str())),
+            _ => return self.get_simple(),
+        }?;
+        self.get_redirects(compound)
+    }
+
+    // A list of commands separated by semicolons or newlines, as found in the body of
+    // compound commands. Stops at whatever reserved word ends the list.
+    pub fn get_list(&mut self) -> Result<Cmd, String> {
+        self.skip_newlines();
+        let mut node = Cmd::Empty;
+        loop {
+            let mut cmd = self.get_and()?;
+            // `&` separates commands just like `;` does
+            let separated = match self.lexer.peek() {
+                Some(Punct(Punct::Semicolon)) | Some(Punct(Punct::Newline)) => {
+                    self.lexer.next();
+                    true
+                }
+                Some(Op(Op::Ampersand)) => {
+                    self.lexer.next();
+                    cmd = Cmd::Async(Box::new(cmd));
+                    true
+                }
+                _ => false,
+            };
+            node = match node {
+                Cmd::Empty => cmd,
+                node => Cmd::Sequence(Box::new(node), Box::new(cmd)),
+            };
+            self.skip_newlines();
+            if !separated || self.at_list_end() {
+                break;
+            }
+        }
+        Ok(node)
+    }
+
+    // `if` has already been consumed by the time we're here
+    fn get_if(&mut self) -> Result<Cmd, String> {
+        let cond = self.get_list()?;
+        self.expect(Reserved::Then)?;
+        let body = self.get_list()?;
+        let otherwise = match self.lexer.next() {
+            Some(Reserved(Reserved::Elif)) => Some(Box::new(self.get_if()?)),
+            Some(Reserved(Reserved::Else)) => {
+                let otherwise = self.get_list()?;
+                self.expect(Reserved::Fi)?;
+                Some(Box::new(otherwise))
+            }
+            Some(Reserved(Reserved::Fi)) => None,
+            _ => return Err(String::from("rush: expected `fi` but found none")),
+        };
+        Ok(Cmd::If(Box::new(cond), Box::new(body), otherwise))
+    }
+
+    // The condition and body of a `while` or `until`
+    fn get_loop(&mut self) -> Result<(Cmd, Cmd), String> {
+        let cond = self.get_list()?;
+        let body = self.get_do()?;
+        Ok((cond, body))
+    }
+
+    // `in` and the `do` of `for name do` aren't in a spot where the lexer would
+    // call them reserved, so they show up here as plain words
+    fn get_for(&mut self) -> Result<Cmd, String> {
+        let name = match self.lexer.next() {
+            Some(Word(w)) => match &w[..] {
+                [Literal(s)] if is_name(s) => s.clone(),
+                _ => return Err(String::from("rush: bad for loop variable")),
+            },
+            _ => return Err(String::from("rush: expected name after `for` but found none")),
+        };
+        self.skip_newlines();
+        let words = if self.peek_word("in") {
             self.lexer.next();
-            Ok(Cmd::Not(Box::new(self.get_simple()?)))
+            let mut words = Vec::new();
+            while let Some(word) = self.next_word() {
+                words.push(word);
+            }
+            match self.lexer.next() {
+                Some(Punct(Punct::Semicolon)) | Some(Punct(Punct::Newline)) => (),
+                _ => return Err(String::from("rush: expected `do` but found none")),
+            }
+            Some(words)
         } else {
-            let mut result = Vec::new();
-            let mut io = Io::new();
-            let mut map = HashMap::new();
+            if let Some(Punct(Punct::Semicolon)) = self.lexer.peek() {
+                self.lexer.next();
+            }
+            None
+        };
+        self.skip_newlines();
+        if self.peek_word("do") {
+            self.lexer.next();
+            let body = self.get_list()?;
+            self.expect(Reserved::Done)?;
+            Ok(Cmd::For(name, words, Box::new(body)))
+        } else {
+            Ok(Cmd::For(name, words, Box::new(self.get_do()?)))
+        }
+    }
 
-            loop {
-                match self.lexer.peek() {
-                    Some(Word(_)) => {
-                        if let Some(Word(mut expansions)) = self.lexer.next() {
-                            if let [Literal(_)] = &expansions[..] {
-                                result.push(expansions.pop().unwrap().get_name())
-                            } else {
-                                let word = self.expand_word(expansions);
-                                if !word.is_empty() {
-                                    result.push(word)
-                                }
-                            }
-                        }
-                    }
-                    Some(Assign(_, _)) => {
-                        if let Some(Assign(key, var)) = self.lexer.next() {
-                            map.insert(key, self.expand_word(var));
-                        }
-                    }
-                    Some(Op(Op::Less)) => {
-                        self.lexer.next();
-                        io.set_stdin(self.token_to_fd(&io)?);
-                    }
-                    Some(Op(Op::More)) => {
-                        self.lexer.next();
-                        io.set_stdout(self.token_to_fd(&io)?);
-                    }
-                    Some(Integer(_)) => {
-                        if let Some(Integer(int)) = self.lexer.next() {
-                            if let Some(Op(_)) = self.lexer.peek() {
-                                self.lexer.next();
-                                match int {
-                                    0 => io.set_stdin(self.token_to_fd(&io)?),
-                                    1 => io.set_stdout(self.token_to_fd(&io)?),
-                                    2 => io.set_stderr(self.token_to_fd(&io)?),
-                                    _ => todo!(),
-                                }
-                            } else {
-                                result.push(int.to_string());
-                            }
-                        }
-                    }
-                    _ => break,
+    // Like `for`, `in` shows up as a plain word, and `esac` might as well if it comes
+    // right after.
+    fn get_case(&mut self) -> Result<Cmd, String> {
+        let word = match self.next_word() {
+            Some(word) => word,
+            None => return Err(String::from("rush: expected word after `case` but found none")),
+        };
+        self.skip_newlines();
+        if !self.peek_word("in") {
+            return Err(String::from("rush: expected `in` but found none"));
+        }
+        self.lexer.next();
+        self.skip_newlines();
+
+        let mut items = Vec::new();
+        loop {
+            if self.peek_word("esac") {
+                self.lexer.next();
+                break;
+            }
+            match self.lexer.peek() {
+                Some(Reserved(Reserved::Esac)) => {
+                    self.lexer.next();
+                    break;
+                }
+                Some(Punct(Punct::LParen)) => {
+                    self.lexer.next();
                 }
+                _ => (),
             }
-            if result.is_empty() {
-                if map.is_empty() {
-                    Err(String::from("rush: expected command but found none"))
-                } else {
-                    map = map
-                        .into_iter()
-                        .filter_map(|(k, v)| {
-                            if env::var_os(&k).is_some() {
-                                env::set_var(k, v);
-                                None
-                            } else {
-                                Some((k, v))
-                            }
-                        })
-                        .collect();
-                    self.shell.borrow_mut().vars.extend(map);
-                    Ok(Cmd::Empty)
+
+            let mut patterns = Vec::new();
+            loop {
+                match self.next_word() {
+                    Some(word) => patterns.push(word),
+                    None => return Err(String::from("rush: expected pattern but found none")),
                 }
-            } else {
-                let mut cmd = Simple::new(result.remove(0), result, io);
-                if !map.is_empty() {
-                    cmd.add_env(map);
+                match self.lexer.next() {
+                    Some(Op(Op::Pipe)) => (),
+                    Some(Punct(Punct::RParen)) => break,
+                    _ => return Err(String::from("rush: expected `)` but found none")),
                 }
-                Ok(Cmd::Simple(cmd))
+            }
+
+            self.skip_newlines();
+            let body = if self.at_list_end() { Cmd::Empty } else { self.get_list()? };
+            items.push((patterns, body));
+
+            match self.lexer.next() {
+                Some(Punct(Punct::DoubleSemicolon)) => self.skip_newlines(),
+                Some(Reserved(Reserved::Esac)) => break,
+                _ => return Err(String::from("rush: expected `esac` but found none")),
             }
         }
+        Ok(Cmd::Case(word, items))
     }
 
-    fn expand_word(&mut self, expansions: Vec<Expand>) -> String {
-        let mut phrase = String::new();
-        for word in expansions {
-            match word {
-                Literal(s) => phrase.push_str(&s),
-                Tilde(word) => {
-                    let s = self.expand_word(word);
-                    if s.is_empty() || s.starts_with('/') {
-                        phrase.push_str(&env::var("HOME").unwrap());
-                        phrase.push_str(&s);
-                    } else {
-                        let mut strings = s.splitn(1, '/');
-                        let name = strings.next().unwrap();
-                        if let Some(user) = User::from_name(name).unwrap() {
-                            phrase.push_str(user.dir.as_os_str().to_str().unwrap());
-                            if let Some(path) = strings.next() {
-                                phrase.push_str(path);
-                            }
-                        } else {
-                            phrase.push('~');
-                            phrase.push_str(name);
-                        }
+    // The name and parentheses have already been consumed by the time we're here
+    fn get_function(&mut self, name: String) -> Result<Cmd, String> {
+        if !is_name(&name) {
+            return Err(format!("rush: `{}': not a valid function name", name));
+        }
+        if let Some(Punct(Punct::RParen)) = self.lexer.next() {
+            self.skip_newlines();
+            Ok(Cmd::Function(name, Rc::new(self.get_command()?)))
+        } else {
+            Err(String::from("rush: expected `)` but found none"))
+        }
+    }
+
+    // Words in spots where the lexer might not have given us a plain word
+    fn next_word(&mut self) -> Option<Vec<Expand>> {
+        match self.lexer.peek() {
+            Some(Word(_)) | Some(Assign(_, _)) | Some(Reserved(_)) => (),
+            _ => return None,
+        }
+        match self.lexer.next() {
+            Some(Word(word)) => Some(word),
+            Some(Assign(key, mut val)) => {
+                val.insert(0, Literal(format!("{}=", key)));
+                Some(val)
+            }
+            Some(Reserved(r)) => Some(vec![Literal(String::from(r.as_str()))]),
+            _ => None,
+        }
+    }
+
+    fn peek_word(&mut self, word: &str) -> bool {
+        if let Some(Word(w)) = self.lexer.peek() {
+            matches!(&w[..], [Literal(s)] if s == word)
+        } else {
+            false
+        }
+    }
+
+    fn get_do(&mut self) -> Result<Cmd, String> {
+        self.expect(Reserved::Do)?;
+        let body = self.get_list()?;
+        self.expect(Reserved::Done)?;
+        Ok(body)
+    }
+
+    fn expect(&mut self, reserved: Reserved) -> Result<(), String> {
+        if let Some(Reserved(r)) = self.lexer.peek() {
+            if *r == reserved {
+                self.lexer.next();
+                return Ok(());
+            }
+        }
+        Err(format!("rush: expected `{}` but found none", reserved.as_str()))
+    }
+
+    fn skip_newlines(&mut self) {
+        while let Some(Punct(Punct::Newline)) = self.lexer.peek() {
+            self.lexer.next();
+        }
+    }
+
+    fn at_list_end(&mut self) -> bool {
+        matches!(
+            self.lexer.peek(),
+            None | Some(Reserved(Reserved::Then))
+                | Some(Reserved(Reserved::Elif))
+                | Some(Reserved(Reserved::Else))
+                | Some(Reserved(Reserved::Fi))
+                | Some(Reserved(Reserved::Do))
+                | Some(Reserved(Reserved::Done))
+                | Some(Reserved(Reserved::Esac))
+                | Some(Reserved(Reserved::RBrace))
+                | Some(Punct(Punct::DoubleSemicolon))
+                | Some(Punct(Punct::RParen))
+        )
+    }
+
+    pub fn get_simple(&mut self) -> Result<Cmd, String> {
+        let mut words = Vec::new();
+        let mut assigns = Vec::new();
+        let mut io = Io::new();
+        let mut redirected = false;
+
+        loop {
+            match self.lexer.peek() {
+                Some(Word(_)) => {
+                    if let Some(Word(word)) = self.lexer.next() {
+                        words.push(word);
                     }
                 }
-                Var(s) => {
-                    phrase.push_str(
-                        &self.shell
-                            .borrow()
-                            .get_var(&s)
-                            .unwrap_or_default()
-                    );
+                Some(Punct(Punct::LParen)) if words.len() == 1 && assigns.is_empty() => {
+                    self.lexer.next();
+                    return match &words.pop().unwrap()[..] {
+                        [Literal(name)] => self.get_function(name.clone()),
+                        word => Err(format!("rush: `{}': not a valid function name", unquote(word))),
+                    };
                 }
-                Brace(key, action, word) => {
-                    let val = self.shell.borrow().get_var(&key);
-                    match action {
-                        Action::UseDefault(null) => {
-                            if let Some(s) = val {
-                                if s == "" && null {
-                                    phrase.push_str(&self.expand_word(word))
-                                } else {
-                                    phrase.push_str(&s)
-                                }
-                            } else {
-                                phrase.push_str(&self.expand_word(word))
-                            }
-                        }
-                        Action::AssignDefault(null) => {
-                            if let Some(s) = val {
-                                if s == "" && null {
-                                    let expanded = self.expand_word(word);
-                                    phrase.push_str(&expanded);
-                                    self.shell.borrow_mut().set_var(key, expanded);
-                                } else {
-                                    phrase.push_str(&s)
-                                }
-                            } else {
-                                let expanded = self.expand_word(word);
-                                phrase.push_str(&expanded);
-                                self.shell.borrow_mut().set_var(key, expanded);
-                            }
-                        }
-                        Action::IndicateError(null) => {
-                            if let Some(s) = val {
-                                if s == "" && null {
-                                    let message = self.expand_word(word);
-                                    if message.is_empty() {
-                                        eprintln!("rush: {}: parameter null", key);
-                                    } else {
-                                        eprintln!("rush: {}: {}", key, message);
-                                    }
-                                    if !self.shell.borrow().is_interactive() {
-                                        exit(1);
-                                    }
-                                } else {
-                                    phrase.push_str(&s)
-                                }
-                            } else {
-                                let message = self.expand_word(word);
-                                if message.is_empty() {
-                                    eprintln!("rush: {}: parameter not set", key);
-                                } else {
-                                    eprintln!("rush: {}: {}", key, message);
-                                }
-                                if !self.shell.borrow().is_interactive() {
-                                    exit(1);
-                                }
-                            }
-                        }
-                        Action::UseAlternate(null) => {
-                            if let Some(s) = val {
-                                if s != "" || !null {
-                                    phrase.push_str(&self.expand_word(word))
-                                }
-                            }
-                        }
-                        Action::RmSmallestSuffix => todo!(),
-                        Action::RmLargestSuffix => todo!(),
-                        Action::RmSmallestPrefix => todo!(),
-                        Action::RmLargestPrefix => todo!(),
-                        Action::StringLength => todo!(),
+                // Once there's a command, anything that looks like an assignment is just a word
+                Some(Assign(_, _)) if !words.is_empty() => {
+                    if let Some(word) = self.next_word() {
+                        words.push(word);
+                    }
+                }
+                Some(Assign(_, _)) => {
+                    if let Some(Assign(key, val)) = self.lexer.next() {
+                        assigns.push((key, val));
                     }
                 }
-                Sub(e) => {
-                    // FIXME: `$(ls something)`, commands with params don't work atm
-                    // for some reason
-                    let mut parser = Parser::new(vec!(Word(e)).into_iter(), Rc::clone(&self.shell));
-
-                    // This setup here allows me to do a surprisingly easy subshell.
-                    // Though subshells typically seem to inherit everything I'm keeping in my
-                    // `shell` variable at the moment?
-                    if let Ok(command) = parser.get() {
-                        #[cfg(debug_assertions)] // Only include when not built with `--release` flag
-                        println!("\u{001b}[33m{:#?}\u{001b}[0m", command);
-
-                        let mut output = Runner::new(Rc::clone(&parser.shell)).execute(command, true).unwrap();
-                        output = output.replace(char::is_whitespace, " ");
-                        phrase.push_str(output.trim());
+                Some(Op(Op::Less)) | Some(Op(Op::More)) | Some(Integer(_)) => {
+                    self.get_redirect(&mut io)?;
+                    redirected = true;
+                }
+                _ => break,
+            }
+        }
+        if words.is_empty() && assigns.is_empty() && !redirected {
+            Err(String::from("rush: expected command but found none"))
+        } else {
+            Ok(Cmd::Simple(Simple::new(words, assigns, io)))
+        }
+    }
+
+    // One redirection onto `io`, if that's what's next
+    fn get_redirect(&mut self, io: &mut Io) -> Result<bool, String> {
+        match self.lexer.peek() {
+            Some(Op(Op::Less)) => {
+                self.lexer.next();
+                io.set_stdin(self.token_to_fd(io, false)?);
+            }
+            Some(Op(Op::More)) => {
+                self.lexer.next();
+                io.set_stdout(self.token_to_fd(io, true)?);
+            }
+            Some(Integer(_)) => {
+                // The lexer only hands out integers right before a redirection
+                if let Some(Integer(int)) = self.lexer.next() {
+                    let output = matches!(self.lexer.next(), Some(Op(Op::More)));
+                    match int {
+                        0 => io.set_stdin(self.token_to_fd(io, output)?),
+                        1 => io.set_stdout(self.token_to_fd(io, output)?),
+                        2 => io.set_stderr(self.token_to_fd(io, output)?),
+                        n => io.set_other(n as RawFd, self.token_to_fd(io, output)?),
                     }
                 }
             }
+            _ => return Ok(false),
         }
-        phrase
+        Ok(true)
     }
 
-    fn token_to_fd(&mut self, io: &Io) -> Result<Rc<RefCell<Fd>>, String> {
+    // Compound commands can be redirected as a whole, after they've ended
+    fn get_redirects(&mut self, cmd: Cmd) -> Result<Cmd, String> {
+        let mut io = Io::new();
+        let mut redirected = false;
+        while self.get_redirect(&mut io)? {
+            redirected = true;
+        }
+        Ok(if redirected { Cmd::Redirect(Box::new(cmd), io) } else { cmd })
+    }
+
+    // Whether `>` can write over a file is up to `set -C` at the time it runs,
+    // while `>|` always can
+    fn token_to_fd(&mut self, io: &Io, output: bool) -> Result<Rc<RefCell<Fd>>, String> {
         let error = String::from("rush: expected redirection location but found none");
         if let Some(token) = self.lexer.next() {
+            // A number where the file name goes is just a name like any other
+            let token = match token {
+                Integer(i) => Word(vec![Literal(i.to_string())]),
+                token => token,
+            };
             match token {
-                Op(Op::Ampersand) => {
-                    if let Some(Integer(i)) = self.lexer.next() {
-                        Ok(Rc::clone(match i {
-                            0 => &io.stdin,
-                            1 => &io.stdout,
-                            2 => &io.stderr,
-                            _ => todo!(),
-                        }))
-                    } else {
-                        Err(error)
-                    }
-                }
+                Op(Op::Ampersand) => match self.lexer.next() {
+                    // Something redirected earlier in the same command is shared,
+                    // otherwise it's whatever the shell has open already
+                    Some(Word(w)) => match &w[..] {
+                        [Literal(s)] if s == "-" => Ok(Rc::new(RefCell::new(Fd::Closed))),
+                        [Literal(s)] => match s.parse::<RawFd>() {
+                            Ok(n) => match io.get(n) {
+                                Some(fd) => Ok(Rc::clone(fd)),
+                                None => Ok(Rc::new(RefCell::new(Fd::Dup(n)))),
+                            },
+                            Err(_) => Err(format!("rush: {}: bad file descriptor", s)),
+                        },
+                        w => Err(format!("rush: {}: bad file descriptor", unquote(w))),
+                    },
+                    _ => Err(error),
+                },
                 Op(Op::More) => {
                     if let Some(Word(s)) = self.lexer.next() {
-                        Ok(Rc::new(RefCell::new(Fd::FileNameAppend(
-                            self.expand_word(s),
-                        ))))
+                        Ok(Rc::new(RefCell::new(Fd::Unexpanded(Fd::FileNameAppend, s))))
                     } else {
                         Err(error)
                     }
                 }
                 Op(Op::Less) => {
                     if let Some(Word(s)) = self.lexer.next() {
-                        let mut s = self.expand_word(s);
-                        s = format!("{}\n", s);
-                        let (reader, mut writer) = pipe().unwrap();
+                        // The delimiter only has its quotes taken off, and if there were any,
+                        // the document is taken as is. Otherwise it's expanded like it's in
+                        // double quotes.
+                        let quoted = s.iter().any(|e| matches!(e, Quoted(_)));
+                        let end = format!("{}\n", unquote(&s));
+                        let mut doc = String::new();
 
                         while let Some(input) = self.shell.borrow_mut().next_prompt("> ") {
-                            if input == s {
+                            if input == end {
                                 break;
                             } else {
-                                writer.write_all(input.as_bytes()).unwrap();
+                                doc.push_str(&input);
                             }
                         }
-                        Ok(Rc::new(RefCell::new(Fd::PipeIn(reader))))
+                        if quoted {
+                            Ok(Rc::new(RefCell::new(Fd::HereDoc(doc))))
+                        } else {
+                            let expansions = lexer::heredoc(&doc, Rc::clone(&self.shell))?;
+                            Ok(Rc::new(RefCell::new(Fd::Unexpanded(Fd::HereDoc, expansions))))
+                        }
                     } else {
                         Err(error)
                     }
                 }
-                Word(s) => Ok(Rc::new(RefCell::new(Fd::FileName(self.expand_word(s))))),
-                Integer(i) => Ok(Rc::new(RefCell::new(Fd::FileName(i.to_string())))),
+                Op(Op::Pipe) if output => {
+                    if let Some(Word(s)) = self.lexer.next() {
+                        Ok(Rc::new(RefCell::new(Fd::Unexpanded(Fd::FileName, s))))
+                    } else {
+                        Err(error)
+                    }
+                }
+                Word(s) if output => Ok(Rc::new(RefCell::new(Fd::Unexpanded(Fd::FileNameNew, s)))),
+                Word(s) => Ok(Rc::new(RefCell::new(Fd::Unexpanded(Fd::FileNameRead, s)))),
                 _ => Err(error),
             }
         } else {
@@ -387,38 +676,46 @@ impl<I> Parser<I>
     }
 }
 
+// What a word looks like with just its quotes taken off, and nothing expanded
+fn unquote(expansions: &[Expand]) -> String {
+    let mut word = String::new();
+    for part in expansions {
+        match part {
+            Literal(s) => word.push_str(s),
+            Var(s) => {
+                word.push('$');
+                word.push_str(s);
+            }
+            Quoted(part) => word.push_str(&unquote(part)),
+            _ => (),
+        }
+    }
+    word
+}
+
 // TODO: Tests for redirection
 #[cfg(test)]
 mod parser_tests {
     use super::{Cmd, Io, Parser, Simple};
-    use crate::helpers::Shell;
-    use crate::lexer::Lexer;
+    use crate::helpers::{Fd, Shell};
+    use crate::lexer::{Expand::*, Lexer};
     use std::cell::RefCell;
     use std::rc::Rc;
 
+    // A command made of nothing but plain words
+    fn simple(cmd: &str) -> Box<Cmd> {
+        let words = cmd.split(' ').map(|w| vec![Literal(String::from(w))]).collect();
+        Box::new(Cmd::Simple(Simple::new(words, vec![], Io::new())))
+    }
+
     #[test]
     fn test_and() {
         let shell = Rc::new(RefCell::new(Shell::new(None)));
         let lexer = Lexer::new("ls | grep cargo && pwd", Rc::clone(&shell));
         let mut parser = Parser::new(lexer, Rc::clone(&shell));
         let expected = Cmd::And(
-            Box::new(Cmd::Pipeline(
-                Box::new(Cmd::Simple(Simple::new(
-                    String::from("ls"),
-                    vec![],
-                    Io::new(),
-                ))),
-                Box::new(Cmd::Simple(Simple::new(
-                    String::from("grep"),
-                    vec![String::from("cargo")],
-                    Io::new(),
-                ))),
-            )),
-            Box::new(Cmd::Simple(Simple::new(
-                String::from("pwd"),
-                vec![],
-                Io::new(),
-            ))),
+            Box::new(Cmd::Pipeline(simple("ls"), simple("grep cargo"))),
+            simple("pwd"),
         );
         assert_eq!(expected, parser.get().unwrap())
     }
@@ -428,31 +725,187 @@ mod parser_tests {
         let shell = Rc::new(RefCell::new(Shell::new(None)));
         let lexer = Lexer::new("ls | grep cargo", Rc::clone(&shell));
         let mut parser = Parser::new(lexer, Rc::clone(&shell));
+        let expected = Cmd::Pipeline(simple("ls"), simple("grep cargo"));
+        assert_eq!(expected, parser.get().unwrap())
+    }
+
+    #[test]
+    fn test_if() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("if true; then ls; elif false; then pwd; else date; fi", Rc::clone(&shell));
+        let mut parser = Parser::new(lexer, Rc::clone(&shell));
+        let expected = Cmd::If(
+            simple("true"),
+            simple("ls"),
+            Some(Box::new(Cmd::If(simple("false"), simple("pwd"), Some(simple("date"))))),
+        );
+        assert_eq!(expected, parser.get().unwrap())
+    }
+
+    #[test]
+    fn test_loops() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("while true; do until false; do break 2; done; done", Rc::clone(&shell));
+        let mut parser = Parser::new(lexer, Rc::clone(&shell));
+        let expected = Cmd::While(
+            simple("true"),
+            Box::new(Cmd::Until(simple("false"), simple("break 2"))),
+        );
+        assert_eq!(expected, parser.get().unwrap())
+    }
+
+    #[test]
+    fn test_for() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("for i in a $b; do ls; done; for j do pwd; done", Rc::clone(&shell));
+        let mut parser = Parser::new(lexer, Rc::clone(&shell));
+        let expected = Cmd::Sequence(
+            Box::new(Cmd::For(
+                String::from("i"),
+                Some(vec![vec![Literal(String::from("a"))], vec![Var(String::from("b"))]]),
+                simple("ls"),
+            )),
+            Box::new(Cmd::For(String::from("j"), None, simple("pwd"))),
+        );
+        assert_eq!(expected, parser.get().unwrap())
+    }
+
+    #[test]
+    fn test_case() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("case x in a|'*'b) ls;; (*) ;; esac", Rc::clone(&shell));
+        let mut parser = Parser::new(lexer, Rc::clone(&shell));
+        let expected = Cmd::Case(
+            vec![Literal(String::from("x"))],
+            vec![
+                (
+                    vec![
+                        vec![Literal(String::from("a"))],
+                        vec![Quoted(vec![Literal(String::from("*"))]), Literal(String::from("b"))],
+                    ],
+                    *simple("ls"),
+                ),
+                (vec![vec![Literal(String::from("*"))]], Cmd::Empty),
+            ],
+        );
+        assert_eq!(expected, parser.get().unwrap())
+    }
+
+    #[test]
+    fn test_function() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("f() while true; do ls; done", Rc::clone(&shell));
+        let mut parser = Parser::new(lexer, Rc::clone(&shell));
+        let expected = Cmd::Function(String::from("f"), Rc::new(Cmd::While(simple("true"), simple("ls"))));
+        assert_eq!(expected, parser.get().unwrap())
+    }
+
+    #[test]
+    fn test_groups() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("{ ls; pwd; } | (date)", Rc::clone(&shell));
+        let mut parser = Parser::new(lexer, Rc::clone(&shell));
         let expected = Cmd::Pipeline(
-            Box::new(Cmd::Simple(Simple::new(
-                String::from("ls"),
-                vec![],
-                Io::new(),
-            ))),
-            Box::new(Cmd::Simple(Simple::new(
-                String::from("grep"),
-                vec![String::from("cargo")],
-                Io::new(),
-            ))),
+            Box::new(Cmd::Sequence(simple("ls"), simple("pwd"))),
+            Box::new(Cmd::Subshell(simple("date"))),
         );
         assert_eq!(expected, parser.get().unwrap())
     }
 
     #[test]
-    fn test_simple() {
+    fn test_sequence() {
         let shell = Rc::new(RefCell::new(Shell::new(None)));
-        let lexer = Lexer::new("ls -ltr", Rc::clone(&shell));
+        let lexer = Lexer::new("ls; pwd # a comment\ndate\n", Rc::clone(&shell));
+        let mut parser = Parser::new(lexer, Rc::clone(&shell));
+        assert_eq!(Cmd::Sequence(simple("ls"), simple("pwd")), parser.get().unwrap());
+        assert_eq!(*simple("date"), parser.get().unwrap());
+    }
+
+    #[test]
+    fn test_get_all() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("ls\n\npwd; date", Rc::clone(&shell));
+        let mut parser = Parser::new(lexer, Rc::clone(&shell));
+        let expected = Cmd::Sequence(simple("ls"), Box::new(Cmd::Sequence(simple("pwd"), simple("date"))));
+        assert_eq!(expected, parser.get_all().unwrap());
+    }
+
+    #[test]
+    fn test_async() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("sleep & ls\n{ date & }\n", Rc::clone(&shell));
+        let mut parser = Parser::new(lexer, Rc::clone(&shell));
+        assert_eq!(Cmd::Sequence(Box::new(Cmd::Async(simple("sleep"))), simple("ls")), parser.get().unwrap());
+        assert_eq!(Cmd::Async(simple("date")), parser.get().unwrap());
+    }
+
+    #[test]
+    fn test_assign() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("a=1 b=$a\nc=2 env d=3\n", Rc::clone(&shell));
         let mut parser = Parser::new(lexer, Rc::clone(&shell));
         let expected = Cmd::Simple(Simple::new(
-            String::from("ls"),
-            vec![String::from("-ltr")],
+            vec![],
+            vec![
+                (String::from("a"), vec![Literal(String::from("1"))]),
+                (String::from("b"), vec![Var(String::from("a"))]),
+            ],
             Io::new(),
         ));
-        assert_eq!(expected, parser.get().unwrap())
+        assert_eq!(expected, parser.get().unwrap());
+        let expected = Cmd::Simple(Simple::new(
+            vec![vec![Literal(String::from("env"))], vec![Literal(String::from("d=")), Literal(String::from("3"))]],
+            vec![(String::from("c"), vec![Literal(String::from("2"))])],
+            Io::new(),
+        ));
+        assert_eq!(expected, parser.get().unwrap());
+    }
+
+    #[test]
+    fn test_simple() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("ls -ltr", Rc::clone(&shell));
+        let mut parser = Parser::new(lexer, Rc::clone(&shell));
+        assert_eq!(*simple("ls -ltr"), parser.get().unwrap())
+    }
+
+    #[test]
+    fn test_descriptors() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("exec 3>log 2>&3 4>&5 >&-", Rc::clone(&shell));
+        let mut parser = Parser::new(lexer, Rc::clone(&shell));
+        if let Cmd::Simple(simple) = parser.get().unwrap() {
+            assert_eq!(vec![3, 4], simple.others.iter().map(|(n, _)| *n).collect::<Vec<_>>());
+            assert!(Rc::ptr_eq(&simple.stderr, &simple.others[0].1));
+            assert_eq!(Fd::Dup(5), *simple.others[1].1.borrow());
+            assert_eq!(Fd::Closed, *simple.stdout.borrow());
+            // Copies share the same way the originals do
+            let io = simple.copy_io();
+            assert!(Rc::ptr_eq(&io.stderr, &io.others[0].1));
+            assert!(!Rc::ptr_eq(&io.stdout, &io.others[1].1));
+        } else {
+            panic!("expected a simple command");
+        }
+    }
+
+    #[test]
+    fn test_compound_redirects() {
+        let shell = Rc::new(RefCell::new(Shell::new(None)));
+        let lexer = Lexer::new("{ ls; } > f 2>&1\nwhile true; do ls; done < f\n", Rc::clone(&shell));
+        let mut parser = Parser::new(lexer, Rc::clone(&shell));
+        match parser.get().unwrap() {
+            Cmd::Redirect(cmd, io) => {
+                assert_eq!(*simple("ls"), *cmd);
+                assert!(Rc::ptr_eq(&io.stdout, &io.stderr));
+            }
+            cmd => panic!("expected a redirect but found {:?}", cmd),
+        }
+        match parser.get().unwrap() {
+            Cmd::Redirect(cmd, io) => {
+                assert_eq!(Cmd::While(simple("true"), simple("ls")), *cmd);
+                assert_eq!(Fd::Unexpanded(Fd::FileNameRead, vec![]), *io.stdin.borrow());
+            }
+            cmd => panic!("expected a redirect but found {:?}", cmd),
+        }
     }
 }
@@ -0,0 +1,320 @@
+// Shell pattern matching, used by `case` and anything else that needs to match
+// against a pattern:
+// https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_13
+// A backslash in a pattern means the next character is taken as is, which is
+// how quoted parts of a pattern are passed in.
+use std::fs;
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Char(char),
+    Any,
+    Star,
+    // Whether it's negated, and what's in the brackets
+    Bracket(bool, Vec<Item>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Item {
+    Char(char),
+    Range(char, char),
+    Class(String),
+}
+
+impl Token {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Char(x) => *x == c,
+            Token::Any => true,
+            Token::Star => false,
+            Token::Bracket(negated, items) => items.iter().any(|item| item.matches(c)) != *negated,
+        }
+    }
+}
+
+impl Item {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Item::Char(x) => *x == c,
+            Item::Range(start, end) => *start <= c && c <= *end,
+            Item::Class(class) => match &class[..] {
+                "alnum" => c.is_alphanumeric(),
+                "alpha" => c.is_alphabetic(),
+                "blank" => c == ' ' || c == '\t',
+                "cntrl" => c.is_control(),
+                "digit" => c.is_ascii_digit(),
+                "graph" => c.is_ascii_graphic(),
+                "lower" => c.is_lowercase(),
+                "print" => c.is_ascii_graphic() || c == ' ',
+                "punct" => c.is_ascii_punctuation(),
+                "space" => c.is_whitespace(),
+                "upper" => c.is_uppercase(),
+                "xdigit" => c.is_ascii_hexdigit(),
+                _ => false,
+            },
+        }
+    }
+}
+
+fn tokenize(pattern: &str) -> Vec<Token> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                tokens.push(Token::Char(chars[i]));
+            }
+            '*' => {
+                // Several stars in a row are no different from one
+                if tokens.last() != Some(&Token::Star) {
+                    tokens.push(Token::Star);
+                }
+            }
+            '?' => tokens.push(Token::Any),
+            '[' => {
+                if let Some((token, len)) = bracket(&chars[i + 1..]) {
+                    tokens.push(token);
+                    i += len;
+                } else {
+                    // A bracket that's never closed is just a bracket
+                    tokens.push(Token::Char('['));
+                }
+            }
+            c => tokens.push(Token::Char(c)),
+        }
+        i += 1;
+    }
+    tokens
+}
+
+// Reads a bracket expression, given everything after the opening bracket.
+// Gives back the token and how many characters it took up.
+fn bracket(chars: &[char]) -> Option<(Token, usize)> {
+    let mut i = 0;
+    let negated = matches!(chars.first(), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+    let mut items = Vec::new();
+    let start = i;
+    loop {
+        let c = *chars.get(i)?;
+        match c {
+            // A closing bracket right at the start is taken as is
+            ']' if i > start => return Some((Token::Bracket(negated, items), i + 1)),
+            '[' if chars.get(i + 1) == Some(&':') => {
+                let rest: String = chars[i + 2..].iter().collect();
+                let end = rest.find(":]")?;
+                items.push(Item::Class(rest[..end].to_string()));
+                i += rest[..end].chars().count() + 4;
+                continue;
+            }
+            '\\' => {
+                i += 1;
+                items.push(Item::Char(*chars.get(i)?));
+            }
+            _ => items.push(Item::Char(c)),
+        }
+        i += 1;
+        // Ranges only make sense between two plain characters
+        if chars.get(i) == Some(&'-') && chars.get(i + 1).is_some_and(|c| *c != ']') {
+            if let Some(Item::Char(from)) = items.last() {
+                let from = *from;
+                let mut to = chars[i + 1];
+                i += 2;
+                if to == '\\' {
+                    to = *chars.get(i)?;
+                    i += 1;
+                }
+                items.pop();
+                items.push(Item::Range(from, to));
+            }
+        }
+    }
+}
+
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let tokens = tokenize(pattern);
+    let text: Vec<char> = text.chars().collect();
+
+    // Whenever something doesn't match, go back to the last star and have it
+    // take one more character
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        match tokens.get(p) {
+            Some(Token::Star) => {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some(token) if token.matches(text[t]) => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            _ => (),
+        }
+        if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    tokens[p..].iter().all(|token| *token == Token::Star)
+}
+
+// Whether there's anything in the pattern that can match more than just itself
+pub fn has_wildcards(pattern: &str) -> bool {
+    tokenize(pattern).iter().any(|token| !matches!(token, Token::Char(_)))
+}
+
+// Pathname expansion: every path the pattern matches, sorted. A slash is only ever
+// matched by a slash in the pattern, and files starting with a dot only match
+// when the pattern says so explicitly.
+pub fn glob(pattern: &str) -> Vec<String> {
+    let mut paths = vec![String::new()];
+    for (i, component) in pattern.split('/').enumerate() {
+        if i > 0 {
+            for path in &mut paths {
+                path.push('/');
+            }
+        }
+        if component.is_empty() {
+            continue;
+        }
+        if !has_wildcards(component) {
+            let name = unescape(component);
+            for path in &mut paths {
+                path.push_str(&name);
+            }
+            continue;
+        }
+        let dot = tokenize(component).first() == Some(&Token::Char('.'));
+        let mut matched = Vec::new();
+        for path in paths {
+            let dir = if path.is_empty() { "." } else { &path };
+            if let Ok(entries) = fs::read_dir(dir) {
+                for name in entries.filter_map(|e| e.ok()?.file_name().into_string().ok()) {
+                    if (dot || !name.starts_with('.')) && matches(component, &name) {
+                        matched.push(format!("{}{}", path, name));
+                    }
+                }
+            }
+        }
+        paths = matched;
+    }
+    // Parts without wildcards were never checked along the way
+    paths.retain(|path| fs::symlink_metadata(path).is_ok());
+    paths.sort();
+    paths
+}
+
+// Where the text could be cut in two, from the start to the end
+fn cuts(text: &str) -> Vec<usize> {
+    text.char_indices().map(|(i, _)| i).chain(Some(text.len())).collect()
+}
+
+// Takes the shortest or longest start of the text that matches off of it,
+// as in `${var#pattern}` and `${var##pattern}`
+pub fn remove_prefix<'a>(pattern: &str, text: &'a str, largest: bool) -> &'a str {
+    let mut cuts = cuts(text);
+    if largest {
+        cuts.reverse();
+    }
+    cuts.into_iter().find(|i| matches(pattern, &text[..*i])).map_or(text, |i| &text[i..])
+}
+
+// Same thing for the end, as in `${var%pattern}` and `${var%%pattern}`
+pub fn remove_suffix<'a>(pattern: &str, text: &'a str, largest: bool) -> &'a str {
+    let mut cuts = cuts(text);
+    if !largest {
+        cuts.reverse();
+    }
+    cuts.into_iter().find(|i| matches(pattern, &text[*i..])).map_or(text, |i| &text[..i])
+}
+
+// Undoes `escape`, for the parts of a pattern that are taken as is
+fn unescape(pattern: &str) -> String {
+    let mut text = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            text.extend(chars.next());
+        } else {
+            text.push(c);
+        }
+    }
+    text
+}
+
+// Makes all of the text match only itself
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '?' | '[' | ']' | '!' | '^' | '-') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod pattern_tests {
+    use super::{escape, glob, has_wildcards, matches, remove_prefix, remove_suffix};
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(!matches("*.rs", "main.rs.bak"));
+        assert!(matches("a*b*c", "aXXbYYbc"));
+        assert!(matches("?", "x"));
+        assert!(!matches("?", ""));
+        assert!(matches("*", ""));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "x"));
+    }
+
+    #[test]
+    fn test_brackets() {
+        assert!(matches("[abc]", "b"));
+        assert!(!matches("[!abc]", "b"));
+        assert!(matches("[a-z]x", "qx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[[:digit:]-]*", "-1"));
+        assert!(matches("[[:digit:]-]*", "7up"));
+        assert!(matches("[", "["));
+        assert!(!matches("[a-c]", "-"));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert!(matches(&escape("*[a]?"), "*[a]?"));
+        assert!(!matches(&escape("*"), "anything"));
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(has_wildcards("src/*.rs"));
+        assert!(!has_wildcards("\\*.rs"));
+        assert!(!has_wildcards("[.rs"));
+        assert_eq!(vec!["src/lexer.rs", "src/lib.rs"], glob("src/l*.rs"));
+        assert_eq!(vec!["src/main.rs"], glob("s?c/main.rs"));
+        assert!(glob("src/nothing*").is_empty());
+    }
+
+    #[test]
+    fn test_remove() {
+        assert_eq!("b/c.tar.gz", remove_prefix("*/", "a/b/c.tar.gz", false));
+        assert_eq!("c.tar.gz", remove_prefix("*/", "a/b/c.tar.gz", true));
+        assert_eq!("a/b/c.tar", remove_suffix(".*", "a/b/c.tar.gz", false));
+        assert_eq!("a/b/c", remove_suffix(".*", "a/b/c.tar.gz", true));
+        assert_eq!("héllo", remove_suffix("x", "héllo", true));
+        assert_eq!("", remove_prefix("*", "héllo", true));
+    }
+}
@@ -1,11 +1,24 @@
 use crate::builtins;
-use crate::helpers::{Fd, Shell};
-use crate::parser::{Cmd, Simple};
+use crate::pattern;
+use crate::expand::Expander;
+use crate::helpers::{Fd, Shell, Skip};
+use crate::lexer::{self, Expand, Lexer};
+use crate::parser::{Cmd, Io, Parser, Simple};
 use os_pipe::{pipe, PipeReader, PipeWriter};
-use std::process::Command;
+use std::process::{Command, ExitStatus};
+use std::os::unix::process::ExitStatusExt;
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::io::Read;
+use std::io::{self, Read, Write};
+use std::process::exit;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::unistd::{close, dup2, execve, fork, ForkResult, Pid};
+use std::convert::Infallible;
+use std::ffi::{CStr, CString};
+use std::path::Path;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 // This is useful to keep track of what each command does with its STDs
 #[derive(Debug)]
@@ -29,10 +42,11 @@ impl CmdMeta {
         }
     }
 
-    fn new_in(self, reader: PipeReader) -> CmdMeta {
+    // Compound commands hand the same stdio to several commands
+    fn try_clone(&self) -> CmdMeta {
         CmdMeta {
-            stdin: Some(reader),
-            stdout: self.stdout,
+            stdin: self.stdin.as_ref().map(|reader| reader.try_clone().unwrap()),
+            stdout: self.stdout.as_ref().map(|writer| writer.try_clone().unwrap()),
         }
     }
 }
@@ -48,117 +62,860 @@ impl Runner {
         }
     }
 
-    pub fn execute(&self, ast: Cmd, capture: bool) -> Option<String> {
-        if capture {
-            let (mut reader, writer) = pipe().unwrap();
-            self.visit(ast, CmdMeta::pipe_out(writer));
-            let mut output = String::new();
-            reader.read_to_string(&mut output).unwrap();
-            Some(output)
-        } else {
-            self.visit(ast, CmdMeta::inherit());
-            None
+    pub fn execute(&self, ast: Cmd) {
+        self.visit(&ast, CmdMeta::inherit());
+    }
+
+    // Reads and runs commands one at a time until the shell's input runs out, since
+    // each one can change how the next is read. Gives back the status of the last one,
+    // or 0 if there was nothing to run.
+    pub fn run(&self) -> i32 {
+        let mut status = 0;
+        loop {
+            let input = self.shell.borrow_mut().next();
+            let line = match input {
+                Some(line) => line,
+                None => break,
+            };
+            let lexer = Lexer::new(&line, Rc::clone(&self.shell));
+            let mut parser = Parser::new(lexer, Rc::clone(&self.shell));
+            match parser.get() {
+                Ok(command) => {
+                    #[cfg(debug_assertions)] // Only include when not built with `--release` flag
+                    println!("\u{001b}[34m{:#?}\u{001b}[0m", command);
+
+                    // `set -n` only makes sense for checking scripts, so a prompt ignores it
+                    if !self.shell.borrow().options.noexec || self.shell.borrow().is_interactive() {
+                        self.execute(command);
+                        status = self.shell.borrow().status;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    self.shell.borrow_mut().status = 2;
+                    status = 2;
+                    // Scripts can't be trusted to do the right thing after a syntax error
+                    if !self.shell.borrow().is_interactive() {
+                        exit(2);
+                    }
+                }
+            }
+            // `break`, `continue` and `return` are for whatever's around `.` or `eval`
+            if self.shell.borrow().skip.is_some() {
+                break;
+            }
         }
+        status
+    }
+
+    // Runs a command substitution in a subshell and hands back what it wrote,
+    // minus any trailing newlines. Its status becomes `$?`.
+    pub fn capture(&self, source: &str) -> String {
+        let lexer = Lexer::new(source.trim_end_matches('\n'), Rc::clone(&self.shell));
+        let command = match Parser::new(lexer, Rc::clone(&self.shell)).get_all() {
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("{}", e);
+                self.shell.borrow_mut().status = 2;
+                return String::new();
+            }
+        };
+        #[cfg(debug_assertions)] // Only include when not built with `--release` flag
+        println!("\u{001b}[33m{:#?}\u{001b}[0m", command);
+
+        let (mut reader, writer) = pipe().unwrap();
+        let child = self.spawn(&command, CmdMeta::pipe_out(writer), || ());
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        self.shell.borrow_mut().status = child.map_or(1, wait);
+        String::from_utf8_lossy(&output).trim_end_matches('\n').to_string()
     }
 
-    // Probably not ideal for all of these to return a bool,
-    // but it works for now. Once I figure out what's non-ideal
-    // about it, I'll fix it
-    fn visit(&self, node: Cmd, stdio: CmdMeta) -> bool {
-        match node {
+    // Everything gives back an exit status, which also becomes `$?`
+    fn visit(&self, node: &Cmd, stdio: CmdMeta) -> i32 {
+        // Nothing runs while we're on our way out of a loop
+        if self.shell.borrow().skip.is_some() {
+            return self.shell.borrow().status;
+        }
+        let status = match node {
             Cmd::Simple(simple) => self.visit_simple(simple, stdio),
-            Cmd::Pipeline(cmd0, cmd1) => self.visit_pipe(*cmd0, *cmd1, stdio),
-            Cmd::And(cmd0, cmd1) => self.visit_and(*cmd0, *cmd1, stdio),
-            Cmd::Or(cmd0, cmd1) => self.visit_or(*cmd0, *cmd1, stdio),
-            Cmd::Not(cmd) => self.visit_not(*cmd, stdio),
-            Cmd::Empty => true,
+            Cmd::Pipeline(cmd0, cmd1) => self.visit_pipe(cmd0, cmd1, stdio),
+            Cmd::And(cmd0, cmd1) => self.visit_and(cmd0, cmd1, stdio),
+            Cmd::Or(cmd0, cmd1) => self.visit_or(cmd0, cmd1, stdio),
+            Cmd::Not(cmd) => self.visit_not(cmd, stdio),
+            Cmd::Sequence(cmd0, cmd1) => self.visit_sequence(cmd0, cmd1, stdio),
+            Cmd::If(cond, body, otherwise) => self.visit_if(cond, body, otherwise.as_deref(), stdio),
+            Cmd::While(cond, body) => self.visit_loop(cond, body, true, stdio),
+            Cmd::Until(cond, body) => self.visit_loop(cond, body, false, stdio),
+            Cmd::For(name, words, body) => self.visit_for(name, words.as_ref(), body, stdio),
+            Cmd::Case(word, items) => self.visit_case(word, items, stdio),
+            Cmd::Subshell(cmd) => self.visit_subshell(cmd, stdio),
+            Cmd::Redirect(cmd, io) => self.visit_redirect(cmd, io, stdio),
+            Cmd::Async(cmd) => self.visit_async(cmd, stdio),
+            Cmd::Function(name, body) => {
+                self.shell.borrow_mut().functions.insert(name.clone(), Rc::clone(body));
+                0
+            }
+            Cmd::Empty => 0,
+        };
+        self.shell.borrow_mut().status = status;
+
+        // Compound commands only fail because something in them did, which has
+        // already had its chance to stop the shell
+        let shell = self.shell.borrow();
+        if status != 0 && shell.options.errexit && shell.conditions == 0 && shell.skip.is_none()
+            && matches!(node, Cmd::Simple(_) | Cmd::Pipeline(_, _) | Cmd::Subshell(_))
+        {
+            exit(status);
+        }
+        status
+    }
+
+    // Conditions are allowed to fail, so `set -e` leaves whatever's in them alone
+    fn visit_condition(&self, node: &Cmd, stdio: CmdMeta) -> i32 {
+        self.shell.borrow_mut().conditions += 1;
+        let status = self.visit(node, stdio);
+        self.shell.borrow_mut().conditions -= 1;
+        status
+    }
+
+    fn visit_sequence(&self, first: &Cmd, second: &Cmd, stdio: CmdMeta) -> i32 {
+        self.visit(first, stdio.try_clone());
+        self.visit(second, stdio)
+    }
+
+    // An `if` whose condition fails and has nowhere else to go still succeeds
+    fn visit_if(&self, cond: &Cmd, body: &Cmd, otherwise: Option<&Cmd>, stdio: CmdMeta) -> i32 {
+        if self.visit_condition(cond, stdio.try_clone()) == 0 {
+            self.visit(body, stdio)
+        } else if let Some(otherwise) = otherwise {
+            self.visit(otherwise, stdio)
+        } else {
+            0
+        }
+    }
+
+    // `while` keeps going as long as the condition is true, `until` as long as it's false.
+    // Results in whatever the last run of the body did, or 0 if it never ran.
+    fn visit_loop(&self, cond: &Cmd, body: &Cmd, keep_going: bool, stdio: CmdMeta) -> i32 {
+        self.shell.borrow_mut().loops += 1;
+        let mut result = 0;
+        loop {
+            let cond = self.visit_condition(cond, stdio.try_clone()) == 0;
+            if self.exit_loop() || cond != keep_going {
+                break;
+            }
+            result = self.visit(body, stdio.try_clone());
+            if self.exit_loop() {
+                break;
+            }
+        }
+        self.shell.borrow_mut().loops -= 1;
+        result
+    }
+
+    fn visit_for(&self, name: &str, words: Option<&Vec<Vec<Expand>>>, body: &Cmd, stdio: CmdMeta) -> i32 {
+        let words = match words {
+            Some(words) => match Expander::new(Rc::clone(&self.shell)).fields(words) {
+                Ok(words) => words,
+                Err(e) => return self.shell_error(e),
+            },
+            None => self.shell.borrow().get_positional().to_vec(),
+        };
+        self.shell.borrow_mut().loops += 1;
+        let mut result = 0;
+        for word in words {
+            let set = self.shell.borrow_mut().set_var(String::from(name), word);
+            if let Err(e) = set {
+                result = self.shell_error(format!("rush: {}", e));
+                break;
+            }
+            result = self.visit(body, stdio.try_clone());
+            if self.exit_loop() {
+                break;
+            }
+        }
+        self.shell.borrow_mut().loops -= 1;
+        result
+    }
+
+    // A compound command's redirections are the shell's for as long as it runs
+    fn visit_redirect(&self, cmd: &Cmd, io: &Io, stdio: CmdMeta) -> i32 {
+        let io = io.copy();
+        let fds = io_fds(&io);
+        if let Err(status) = self.ready_fds(&fds, &mut Expander::new(Rc::clone(&self.shell))) {
+            return status;
+        }
+        self.reconcile_io(&fds, stdio);
+        redirected(&fds, || self.visit(cmd, CmdMeta::inherit()))
+    }
+
+    // The child gets a copy of everything, so whatever it does to itself
+    // is gone when it exits
+    fn visit_subshell(&self, cmd: &Cmd, stdio: CmdMeta) -> i32 {
+        match self.spawn(cmd, stdio, || ()) {
+            Some(child) => wait(child),
+            None => 1,
+        }
+    }
+
+    // Same as a subshell, except nobody waits for it. Without job control there's
+    // no telling what it would read from the terminal, so scripts give it nothing.
+    fn visit_async(&self, cmd: &Cmd, stdio: CmdMeta) -> i32 {
+        let interactive = self.shell.borrow().is_interactive();
+        let setup = || {
+            if !interactive {
+                if let Ok(null) = File::open("/dev/null") {
+                    dup2(null.as_raw_fd(), 0).unwrap();
+                }
+            }
+        };
+        self.shell.borrow_mut().reap_jobs();
+        match self.spawn(cmd, stdio, setup) {
+            Some(child) => {
+                let mut shell = self.shell.borrow_mut();
+                shell.jobs.push(child);
+                shell.last_job = Some(child);
+                0
+            }
+            None => 1,
+        }
+    }
+
+    // Runs the command in a child process, after giving it a chance to set itself up.
+    // The parent's copy of the stdio is dropped once we're back.
+    fn spawn(&self, cmd: &Cmd, stdio: CmdMeta, setup: impl FnOnce()) -> Option<Pid> {
+        io::stdout().flush().unwrap();
+        match fork() {
+            Ok(ForkResult::Child) => {
+                setup();
+                exit(self.visit(cmd, stdio));
+            }
+            Ok(ForkResult::Parent { child }) => Some(child),
+            Err(e) => {
+                eprintln!("rush: {}", e);
+                None
+            }
+        }
+    }
+
+    // Runs the first set of commands with a pattern that matches, if any.
+    // Patterns are only expanded until one of them matches.
+    fn visit_case(&self, word: &[Expand], items: &[(Vec<Vec<Expand>>, Cmd)], stdio: CmdMeta) -> i32 {
+        let mut expander = Expander::new(Rc::clone(&self.shell));
+        let word = match expander.word(word) {
+            Ok(word) => word,
+            Err(e) => return self.shell_error(e),
+        };
+        for (patterns, body) in items {
+            for p in patterns {
+                match expander.pattern(p) {
+                    Ok(p) if pattern::matches(&p, &word) => return self.visit(body, stdio),
+                    Ok(_) => (),
+                    Err(e) => return self.shell_error(e),
+                }
+            }
         }
+        0
     }
 
-    fn visit_not(&self, cmd: Cmd, stdio: CmdMeta) -> bool {
-        let result = self.visit(cmd, stdio);
-        !result
+    // Checks whether a `break` or `continue` means the current loop should stop,
+    // taking care of the ones that are meant for this loop
+    fn exit_loop(&self) -> bool {
+        let mut shell = self.shell.borrow_mut();
+        match shell.skip {
+            Some(Skip::Break(n)) => {
+                shell.skip = if n > 1 { Some(Skip::Break(n - 1)) } else { None };
+                true
+            }
+            Some(Skip::Continue(n)) if n > 1 => {
+                shell.skip = Some(Skip::Continue(n - 1));
+                true
+            }
+            Some(Skip::Continue(_)) => {
+                shell.skip = None;
+                false
+            }
+            Some(Skip::Return(_)) => true,
+            None => false,
+        }
     }
 
-    fn visit_or(&self, left: Cmd, right: Cmd, stdio: CmdMeta) -> bool {
-        let left = self.visit(left, CmdMeta::inherit());
-        if left {
+    fn visit_not(&self, cmd: &Cmd, stdio: CmdMeta) -> i32 {
+        let result = self.visit_condition(cmd, stdio);
+        if result == 0 { 1 } else { 0 }
+    }
+
+    fn visit_or(&self, left: &Cmd, right: &Cmd, stdio: CmdMeta) -> i32 {
+        let left = self.visit_condition(left, stdio.try_clone());
+        if left == 0 {
             left
         } else {
             self.visit(right, stdio)
         }
     }
 
-    fn visit_and(&self, left: Cmd, right: Cmd, stdio: CmdMeta) -> bool {
-        let left = self.visit(left, CmdMeta::inherit());
-        if left {
+    fn visit_and(&self, left: &Cmd, right: &Cmd, stdio: CmdMeta) -> i32 {
+        let left = self.visit_condition(left, stdio.try_clone());
+        if left == 0 {
             self.visit(right, stdio)
         } else {
             left
         }
     }
 
-    // We create a pipe, pass the writing end to the left, and modify the stdio
-    // to have its stdin be the reading end.
-    fn visit_pipe(&self, left: Cmd, right: Cmd, stdio: CmdMeta) -> bool {
-        let (reader, writer) = pipe().unwrap();
-        self.visit(left, CmdMeta::pipe_out(writer));
-        self.visit(right, stdio.new_in(reader))
+    // Every command in the pipeline gets its own process, and they all run at once,
+    // each one writing into a pipe the next one reads from. We only hold on to the
+    // pipes long enough to hand them out, so everyone sees EOF when they should.
+    // The pipeline does whatever the last command does, or with `set -o pipefail`,
+    // whatever the last one to fail does.
+    fn visit_pipe(&self, left: &Cmd, right: &Cmd, stdio: CmdMeta) -> i32 {
+        let mut stages = vec![right];
+        let mut rest = left;
+        while let Cmd::Pipeline(left, right) = rest {
+            stages.push(right);
+            rest = left;
+        }
+        stages.push(rest);
+        stages.reverse();
+
+        let CmdMeta { mut stdin, mut stdout } = stdio;
+        let last = stages.len() - 1;
+        let mut children = Vec::new();
+        for (i, stage) in stages.into_iter().enumerate() {
+            let (next, writer) = if i == last {
+                (None, stdout.take())
+            } else {
+                let (reader, writer) = pipe().unwrap();
+                (Some(reader), Some(writer))
+            };
+            // Anything the parent is still holding on to is none of this stage's business
+            let others: Vec<RawFd> = next.iter().map(|r| r.as_raw_fd())
+                .chain(stdout.iter().map(|w| w.as_raw_fd()))
+                .collect();
+            let stdio = CmdMeta { stdin: stdin.take(), stdout: writer };
+            let child = self.spawn(stage, stdio, || {
+                for fd in others {
+                    let _ = close(fd);
+                }
+            });
+            children.push(child);
+            stdin = next;
+        }
+
+        let statuses: Vec<i32> = children.into_iter().map(|child| child.map_or(1, wait)).collect();
+        if self.shell.borrow().options.pipefail {
+            statuses.into_iter().rev().find(|status| *status != 0).unwrap_or(0)
+        } else {
+            statuses[last]
+        }
     }
 
-    fn visit_simple(&self, mut simple: Simple, stdio: CmdMeta) -> bool {
-        self.reconcile_io(&mut simple, stdio);
-        match &simple.cmd[..] {
-            "exit" => builtins::exit(simple.args),
-            "cd" => builtins::cd(simple.args),
-            "set" => builtins::set(simple.args, &self.shell),
-            command => {
-                let mut cmd = Command::new(command);
-                cmd.args(&simple.args);
+    // The arguments become the positional parameters for the length of the call.
+    // Loops outside of the function are out of reach of `break` and `continue` inside.
+    fn visit_function(&self, body: &Cmd, args: Vec<String>, stdio: CmdMeta) -> i32 {
+        let (pos, loops) = {
+            let mut shell = self.shell.borrow_mut();
+            let pos = shell.get_positional().to_vec();
+            shell.set_pos(args);
+            shell.calls += 1;
+            (pos, std::mem::replace(&mut shell.loops, 0))
+        };
+        let mut result = self.visit(body, stdio);
 
-                if let Some(stdin) = simple.stdin.borrow_mut().get_stdin() {
-                    cmd.stdin(stdin);
-                } else {
-                    return false;
+        let mut shell = self.shell.borrow_mut();
+        if let Some(Skip::Return(returned)) = shell.skip {
+            shell.skip = None;
+            result = returned;
+        }
+        shell.set_pos(pos);
+        shell.calls -= 1;
+        shell.loops = loops;
+        result
+    }
+
+    fn visit_simple(&self, simple: &Simple, stdio: CmdMeta) -> i32 {
+        let mut expander = Expander::new(Rc::clone(&self.shell));
+        let mut args = match expander.fields(&simple.words) {
+            Ok(fields) => fields,
+            Err(e) => return self.shell_error(e),
+        };
+        let mut env = Vec::new();
+        for (key, val) in &simple.assigns {
+            match expander.word(val) {
+                // Without a command, each one is set in time for the next to see it
+                Ok(val) if args.is_empty() => {
+                    let set = self.shell.borrow_mut().set_var(key.clone(), val.clone());
+                    if let Err(e) = set {
+                        return self.shell_error(format!("rush: {}", e));
+                    }
+                    env.push((key.clone(), val));
                 }
-                if let Some(stdout) = simple.stdout.borrow_mut().get_stdout() {
-                    cmd.stdout(stdout);
-                } else {
-                    return false;
+                Ok(val) => env.push((key.clone(), val)),
+                Err(e) => return self.shell_error(e),
+            }
+        }
+
+        if self.shell.borrow().options.xtrace {
+            self.trace(&env, &args);
+        }
+
+        let io = simple.copy_io();
+        let fds = io_fds(&io);
+        if let Err(status) = self.ready_fds(&fds, &mut expander) {
+            return status;
+        }
+        let Io { stdin, stdout, stderr, .. } = &io;
+
+        // Only the redirections are left to do, which still create files and such
+        if args.is_empty() {
+            return match redirect(&fds) {
+                Some(saved) => {
+                    restore(saved);
+                    expander.sub_status.unwrap_or(0)
                 }
-                if let Some(stderr) = simple.stdin.borrow_mut().get_stderr() {
-                    cmd.stderr(stderr);
-                } else {
-                    return false;
+                None => 1,
+            };
+        }
+
+        let command = args.remove(0);
+        self.reconcile_io(&fds, stdio);
+        // The function's redirections are the shell's for as long as it runs, pipes included
+        let function = self.shell.borrow().functions.get(&command).cloned();
+        if let Some(body) = function {
+            return redirected(&fds, || {
+                self.scoped(env, || self.visit_function(&body, args, CmdMeta::inherit()))
+            });
+        }
+
+        if builtins::SPECIAL.contains(&command.as_str()) {
+            for (key, val) in &env {
+                let set = self.shell.borrow_mut().set_var(key.clone(), val.clone());
+                if let Err(e) = set {
+                    return self.shell_error(format!("rush: {}", e));
                 }
-                if let Some(env) = simple.env {
-                    cmd.envs(env);
+            }
+            if command == "exec" {
+                return self.exec(args, &fds, env);
+            }
+            return redirected(&fds, || self.builtin(&command, args));
+        }
+        if builtins::REGULAR.contains(&command.as_str()) {
+            return redirected(&fds, || self.scoped(env, || self.builtin(&command, args)));
+        }
+
+        let mut cmd = Command::new(&command);
+        cmd.args(&args);
+
+        if let Some(stdin) = stdin.borrow_mut().get_stdin() {
+            cmd.stdin(stdin);
+        } else {
+            return 1;
+        }
+        if let Some(stdout) = stdout.borrow_mut().get_stdout() {
+            cmd.stdout(stdout);
+        } else {
+            return 1;
+        }
+        if let Some(stderr) = stderr.borrow_mut().get_stderr() {
+            cmd.stderr(stderr);
+        } else {
+            return 1;
+        }
+        // Only what's been exported goes along, not whatever we were started with,
+        // and the assignments in front of the command go to it alone
+        cmd.env_clear();
+        cmd.envs(self.shell.borrow().exported());
+        cmd.envs(env);
+
+        // Anything past stderr, or closed, has nowhere to go but the shell's own
+        // descriptors, which the command inherits
+        let inherited: Vec<_> = fds.iter()
+            .filter(|(n, fd)| *n > 2 || *fd.borrow() == Fd::Closed)
+            .cloned()
+            .collect();
+        let saved = match redirect(&inherited) {
+            Some(saved) => saved,
+            None => return 1,
+        };
+        let status = cmd.status();
+        restore(saved);
+        match status {
+            Ok(status) => exit_code(status),
+            Err(e) => {
+                eprintln!("rush: {}: {}", command, e);
+                // Same as everyone else: 127 if it's not there, 126 if it can't be run
+                if e.kind() == io::ErrorKind::NotFound { 127 } else { 126 }
+            }
+        }
+    }
+
+    // Without a command, the redirections are for the shell from now on. With one,
+    // the shell turns into it, and there's nothing to come back to unless that fails.
+    fn exec(&self, args: Vec<String>, fds: &[(RawFd, &Rc<RefCell<Fd>>)], env: Vec<(String, String)>) -> i32 {
+        let saved = match redirect(fds) {
+            Some(saved) => saved,
+            None => return 1,
+        };
+        if args.is_empty() {
+            for (_, old) in saved {
+                if let Some(old) = old {
+                    let _ = close(old);
                 }
+            }
+            return 0;
+        }
+
+        let path = self.shell.borrow().find_in_path(&args[0]);
+        let error = match path {
+            Some(path) => {
+                let mut vars = self.shell.borrow().exported();
+                vars.extend(env);
+                match exec_path(&path, &args, vars) {
+                    Ok(never) => match never {},
+                    Err(e) => e,
+                }
+            }
+            None => (String::from("not found"), 127),
+        };
+        restore(saved);
+        eprintln!("rush: exec: {}: {}", args[0], error.0);
+        if !self.shell.borrow().is_interactive() {
+            exit(error.1);
+        }
+        error.1
+    }
+
+    fn builtin(&self, name: &str, args: Vec<String>) -> i32 {
+        match name {
+            ":" => 0,
+            "." => builtins::dot(args, &self.shell),
+            "eval" => builtins::eval(args, &self.shell),
+            "exit" => builtins::exit(args, &self.shell),
+            "cd" => builtins::cd(args, &self.shell),
+            "set" => builtins::set(args, &self.shell),
+            "break" => builtins::break_loop(args, &self.shell),
+            "continue" => builtins::continue_loop(args, &self.shell),
+            "return" => builtins::return_function(args, &self.shell),
+            "export" => builtins::export(args, &self.shell),
+            "readonly" => builtins::readonly(args, &self.shell),
+            "unset" => builtins::unset(args, &self.shell),
+            "shift" => builtins::shift(args, &self.shell),
+            "getopts" => builtins::getopts(args, &self.shell),
+            _ => unreachable!("{} isn't a builtin", name),
+        }
+    }
+
+    // Assignments in front of a function or a regular builtin only last as long as it
+    // does, and anything it runs in the meantime gets them exported
+    fn scoped(&self, assigns: Vec<(String, String)>, run: impl FnOnce() -> i32) -> i32 {
+        let mut saved = Vec::new();
+        let mut error = None;
+        for (key, val) in assigns {
+            let mut shell = self.shell.borrow_mut();
+            let old = shell.vars.get(&key).cloned();
+            if let Err(e) = shell.set_var(key.clone(), val) {
+                error = Some(e);
+                break;
+            }
+            shell.vars.get_mut(&key).unwrap().exported = true;
+            saved.push((key, old));
+        }
+
+        let status = match error {
+            Some(e) => self.shell_error(format!("rush: {}", e)),
+            None => run(),
+        };
+
+        let mut shell = self.shell.borrow_mut();
+        for (key, old) in saved.into_iter().rev() {
+            match old {
+                Some(var) => shell.vars.insert(key, var),
+                None => shell.vars.remove(&key),
+            };
+        }
+        status
+    }
+
+    // `set -x` writes out what's about to run, after `PS4` and everything else
+    // is expanded
+    fn trace(&self, assigns: &[(String, String)], args: &[String]) {
+        let ps4 = self.shell.borrow().get_var("PS4").unwrap_or_else(|| String::from("+ "));
+        let prompt = lexer::heredoc(&ps4, Rc::clone(&self.shell))
+            .and_then(|ps4| Expander::new(Rc::clone(&self.shell)).word(&ps4))
+            .unwrap_or(ps4);
+        let words: Vec<String> = assigns.iter()
+            .map(|(key, val)| format!("{}={}", key, val))
+            .chain(args.iter().cloned())
+            .collect();
+        eprintln!("{}{}", prompt, words.join(" "));
+    }
+
+    // A script can't go on after an expansion or assignment goes wrong, but at a prompt,
+    // it's just that command that fails
+    fn shell_error(&self, e: String) -> i32 {
+        eprintln!("{}", e);
+        if !self.shell.borrow().is_interactive() {
+            exit(2);
+        }
+        1
+    }
+
+    // Expands the redirections, and makes sure any `>&n` has somewhere to go.
+    // If not, there's the status to stop with.
+    fn ready_fds(&self, fds: &[(RawFd, &Rc<RefCell<Fd>>)], expander: &mut Expander) -> Result<(), i32> {
+        let noclobber = self.shell.borrow().options.noclobber;
+        for (_, fd) in fds {
+            if let Err(e) = expand_fd(fd, expander, noclobber) {
+                return Err(self.shell_error(e));
+            }
+        }
+        if check_dups(fds) { Ok(()) } else { Err(1) }
+    }
+
+    // Takes the stdio and if stdio has priority, replaces stdout/stdin with it. That's
+    // wherever they ended up, so `2>&1 >/dev/null` still sends stderr down the pipe.
+    fn reconcile_io(&self, fds: &[(RawFd, &Rc<RefCell<Fd>>)], stdio: CmdMeta) {
+        for (_, fd) in fds {
+            let piped = match (&*fd.borrow(), &stdio) {
+                (Fd::Stdout, CmdMeta { stdout: Some(writer), .. }) => Fd::PipeOut(writer.try_clone().unwrap()),
+                (Fd::Stdin, CmdMeta { stdin: Some(reader), .. }) => Fd::PipeIn(reader.try_clone().unwrap()),
+                _ => continue,
+            };
+            *fd.borrow_mut() = piped;
+        }
+    }
+}
 
-                match cmd.status() {
-                    Ok(child) => child.success(),
-                    Err(e) => {
-                        eprintln!("rush: {}: {}", simple.cmd, e);
-                        false
+// Each fd by its number
+fn io_fds(io: &Io) -> Vec<(RawFd, &Rc<RefCell<Fd>>)> {
+    let mut fds = vec![(0, &io.stdin), (1, &io.stdout), (2, &io.stderr)];
+    fds.extend(io.others.iter().map(|(n, fd)| (*n, fd)));
+    fds
+}
+
+// Redirections that share an Fd, like `>file 2>&1`, only get expanded the once
+fn expand_fd(fd: &Rc<RefCell<Fd>>, expander: &mut Expander, noclobber: bool) -> Result<(), String> {
+    let expanded = match &*fd.borrow() {
+        Fd::Unexpanded(make, word) => match make(expander.word(word)?) {
+            Fd::FileNameNew(name) if !noclobber => Fd::FileName(name),
+            fd => fd,
+        },
+        _ => return Ok(()),
+    };
+    *fd.borrow_mut() = expanded;
+    Ok(())
+}
+
+// Puts redirections in place on the shell's own descriptors, for builtins and such that
+// don't get a process of their own. Everything's opened before anything is moved, so
+// `2>&1 >file` still sees the old stdout. What was there before is kept out of the way
+// to put back afterwards, and if anything can't be opened, nothing changes.
+fn redirect(fds: &[(RawFd, &Rc<RefCell<Fd>>)]) -> Option<Vec<(RawFd, Option<RawFd>)>> {
+    let mut opened = Vec::new();
+    for (n, fd) in fds {
+        match (*n, &*fd.borrow()) {
+            (_, Fd::Inherit) | (0, Fd::Stdin) | (1, Fd::Stdout) | (2, Fd::Stderr) => continue,
+            (_, Fd::Closed) => {
+                opened.push((*n, None));
+                continue;
+            }
+            _ => (),
+        }
+        match fd.borrow_mut().get_raw(*n == 0) {
+            Some(raw) => opened.push((*n, Some(raw))),
+            None => {
+                for (_, raw) in opened {
+                    if let Some(raw) = raw {
+                        let _ = close(raw);
                     }
                 }
+                return None;
+            }
+        }
+    }
+
+    let _ = io::stdout().flush();
+    let mut saved = Vec::new();
+    for (n, raw) in opened {
+        // Kept high up and closed on exec, so nothing we run ever sees it
+        let old = fcntl(n, FcntlArg::F_DUPFD_CLOEXEC(10)).ok();
+        match raw {
+            Some(raw) if raw != n => {
+                let _ = dup2(raw, n);
+                let _ = close(raw);
+            }
+            Some(_) => (),
+            None => {
+                let _ = close(n);
             }
         }
+        saved.push((n, old));
     }
+    Some(saved)
+}
+
+// Runs something with the redirections in place, or fails if they can't be
+fn redirected(fds: &[(RawFd, &Rc<RefCell<Fd>>)], run: impl FnOnce() -> i32) -> i32 {
+    match redirect(fds) {
+        Some(saved) => {
+            let status = run();
+            restore(saved);
+            status
+        }
+        None => 1,
+    }
+}
 
-    // Takes the stdio and if stdio has priority, replaces stdout/stdin with it.
-    fn reconcile_io(&self, simple: &mut Simple, stdio: CmdMeta) {
-        if let Some(stdout) = stdio.stdout {
-            if *simple.stdout.borrow() == Fd::Stdout {
-                *simple.stdout.borrow_mut() = Fd::PipeOut(stdout);
+fn restore(saved: Vec<(RawFd, Option<RawFd>)>) {
+    let _ = io::stdout().flush();
+    for (n, old) in saved.into_iter().rev() {
+        match old {
+            Some(old) => {
+                let _ = dup2(old, n);
+                let _ = close(old);
+            }
+            None => {
+                let _ = close(n);
             }
         }
-        if let Some(stdin) = stdio.stdin {
-            if *simple.stdin.borrow() == Fd::Stdin {
-                *simple.stdin.borrow_mut() = Fd::PipeIn(stdin);
+    }
+}
+
+// Only comes back if something went wrong, with what and the status to go with it
+fn exec_path(path: &Path, args: &[String], vars: Vec<(String, String)>) -> Result<Infallible, (String, i32)> {
+    let cstring = |s: String| CString::new(s).map_err(|e| (e.to_string(), 126));
+    let path = cstring(path.to_string_lossy().into_owned())?;
+    let args = args.iter().cloned().map(cstring).collect::<Result<Vec<_>, _>>()?;
+    let vars = vars.into_iter()
+        .map(|(key, val)| cstring(format!("{}={}", key, val)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let args: Vec<&CStr> = args.iter().map(CString::as_c_str).collect();
+    let vars: Vec<&CStr> = vars.iter().map(CString::as_c_str).collect();
+    let _ = io::stdout().flush();
+    execve(&path, &args, &vars).map_err(|_| {
+        let e = io::Error::last_os_error();
+        let status = if e.kind() == io::ErrorKind::NotFound { 127 } else { 126 };
+        (e.to_string(), status)
+    })
+}
+
+// `>&3` has to be something the shell already has open. That's checked before anything
+// else is opened, since whatever's opened next would take the number.
+fn check_dups(fds: &[(RawFd, &Rc<RefCell<Fd>>)]) -> bool {
+    for (_, fd) in fds {
+        if let Fd::Dup(n) = *fd.borrow() {
+            if fcntl(n, FcntlArg::F_GETFD).is_err() {
+                eprintln!("rush: {}: Bad file descriptor", n);
+                return false;
             }
         }
     }
+    true
 }
-// How do I test this module?
 
+// Killed children report 128 plus the signal that did it, like everywhere else
+fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
+
+fn wait(child: Pid) -> i32 {
+    match waitpid(child, None) {
+        Ok(WaitStatus::Exited(_, code)) => code,
+        Ok(WaitStatus::Signaled(_, signal, _)) => 128 + signal as i32,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod runner_tests {
+    use super::Runner;
+    use crate::helpers::Shell;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+    use std::{env, fs, io, process};
+    use std::rc::Rc;
+
+    // Runs a whole script in a shell of its own, with nothing else to read from
+    fn run(source: &str) -> Rc<RefCell<Shell>> {
+        let shell = Shell::from_input(Box::new(io::empty()), false, String::from("rush"));
+        let shell = Rc::new(RefCell::new(shell));
+        let lexer = Lexer::new(source, Rc::clone(&shell));
+        let command = Parser::new(lexer, Rc::clone(&shell)).get_all().unwrap();
+        Runner::new(Rc::clone(&shell)).execute(command);
+        shell
+    }
+
+    #[test]
+    fn test_function_redirects() {
+        let dir = env::temp_dir().join(format!("rush-functions-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap();
+        run(&format!(
+            "f() {{ echo infunc; }}\nf > {0}/out\nf >> {0}/out\ng() {{ cat; }}\ng < {0}/out > {0}/copy",
+            dir
+        ));
+        assert_eq!("infunc\ninfunc\n", fs::read_to_string(format!("{}/out", dir)).unwrap());
+        assert_eq!("infunc\ninfunc\n", fs::read_to_string(format!("{}/copy", dir)).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_bang() {
+        let shell = run(concat!(
+            "if [ ! -f /nonexistent ]; then a=1; fi\n",
+            "case b in [!a-c]) b=1;; [!x]) b=2;; esac\n",
+            "c=$(echo src/[!a-o]*.rs)",
+        ));
+        let shell = shell.borrow();
+        assert_eq!(Some(String::from("1")), shell.get_var("a"));
+        assert_eq!(Some(String::from("2")), shell.get_var("b"));
+        assert_eq!(Some(String::from("src/parser.rs src/pattern.rs src/runner.rs")), shell.get_var("c"));
+    }
+
+    #[test]
+    fn test_pipeline_status() {
+        assert_eq!(1, run("true | false").borrow().status);
+        assert_eq!(5, run("sh -c 'exit 3' | sh -c 'exit 5'").borrow().status);
+        assert_eq!(0, run("false | false | true").borrow().status);
+    }
+
+    #[test]
+    fn test_status() {
+        let shell = run(concat!(
+            "false; a=$?; true; b=$?\n",
+            "(exit 7); c=$?\n",
+            "sh -c 'kill -9 $$'; d=$?",
+        ));
+        let shell = shell.borrow();
+        assert_eq!(Some(String::from("1")), shell.get_var("a"));
+        assert_eq!(Some(String::from("0")), shell.get_var("b"));
+        assert_eq!(Some(String::from("7")), shell.get_var("c"));
+        // Killed by a signal is 128 plus its number
+        assert_eq!(Some(String::from("137")), shell.get_var("d"));
+    }
+
+    #[test]
+    fn test_pipefail() {
+        assert_eq!(0, run("false | true").borrow().status);
+        assert_eq!(1, run("set -o pipefail; false | true").borrow().status);
+        assert_eq!(0, run("set -o pipefail; true | true").borrow().status);
+    }
+
+    #[test]
+    fn test_prefix_assignments() {
+        let shell = run(concat!(
+            "x=1 getopts a o\n",
+            "f() { y=$x; }; x=2 f\n",
+            "x=3 sh -c 'exit $x'; z=$?",
+        ));
+        let shell = shell.borrow();
+        assert_eq!(None, shell.get_var("x"));
+        assert_eq!(Some(String::from("2")), shell.get_var("y"));
+        assert_eq!(Some(String::from("3")), shell.get_var("z"));
+
+        // Special builtins keep them around
+        assert_eq!(Some(String::from("1")), run("x=1 :").borrow().get_var("x"));
+    }
+}